@@ -0,0 +1,109 @@
+//! Hammers `Logins` and `Sessions` from many threads to measure how well they scale
+//! under concurrent login and authenticate traffic.
+//!
+//! Run with `cargo bench --bench auth`
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+#[allow(dead_code)]
+#[path = "../src/apps/auth/singletons.rs"]
+mod singletons;
+
+use singletons::{Logins, Sessions};
+
+const USERS_PER_THREAD: usize = 64;
+const ROUNDS: usize = 2_000;
+/// How many authenticated requests a user makes per login
+const REQUESTS_PER_LOGIN: usize = 8;
+
+
+fn make_state() -> (Logins, Sessions) {
+	(
+		Logins::new(
+			Duration::from_secs(60),
+			5,
+			16,
+			4,
+			16,
+			Duration::from_millis(10),
+			Regex::new(".{8,}").unwrap(),
+			32
+		),
+		Sessions::new(Duration::from_secs(3600), Duration::from_millis(10), 3)
+	)
+}
+
+
+/// Simulates one user logging in (with the occasional failed attempt) and then using the session
+fn run_user(logins: &Logins, sessions: &Sessions, username: &str, round: usize) {
+	logins.prune_expired();
+	sessions.prune_expired();
+
+	if logins.is_user_locked_out(username).is_some() {
+		return
+	}
+
+	if round % 4 == 0 {
+		logins.mark_failed_login(username.to_string());
+		return
+	}
+
+	logins.mark_succesful_login(username);
+	let session_id = sessions.create_session(username.to_string());
+
+	for _ in 0..REQUESTS_PER_LOGIN {
+		assert_eq!(sessions.get_session_owner(&session_id).as_deref(), Some(username));
+	}
+
+	if round % 3 == 0 {
+		sessions.renew_session(username);
+	}
+	if round % 5 == 0 {
+		sessions.remove_session(username);
+	}
+}
+
+
+fn main() {
+	let max_threads = thread::available_parallelism().map(|x| x.get()).unwrap_or(4);
+	let mut thread_count = 1;
+
+	println!("{:>8} {:>12} {:>14}", "threads", "elapsed ms", "logins/sec");
+
+	while thread_count <= max_threads * 2 {
+		let state = Arc::new(make_state());
+		let start = Instant::now();
+
+		thread::scope(|scope| {
+			for t in 0..thread_count {
+				let state = state.clone();
+				scope.spawn(move || {
+					let usernames: Vec<_> = (0..USERS_PER_THREAD)
+						.map(|i| format!("user{t}x{i}"))
+						.collect();
+
+					for round in 0..ROUNDS {
+						let (logins, sessions) = state.as_ref();
+						run_user(logins, sessions, &usernames[round % USERS_PER_THREAD], round);
+					}
+				});
+			}
+		});
+
+		let elapsed = start.elapsed();
+		let total = (thread_count * ROUNDS) as f64;
+
+		println!(
+			"{:>8} {:>12} {:>14.0}",
+			thread_count,
+			elapsed.as_millis(),
+			total / elapsed.as_secs_f64()
+		);
+
+		thread_count *= 2;
+	}
+}
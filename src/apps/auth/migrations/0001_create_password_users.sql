@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS PasswordUsers (
+    Username TEXT PRIMARY KEY NOT NULL,
+    Salt BLOB NOT NULL,
+    Hash BLOB NOT NULL
+);
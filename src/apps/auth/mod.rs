@@ -1,22 +1,25 @@
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
 use regex::Regex;
 use rocket::{FromForm, async_trait};
 use rocket::form::Form;
 use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::serde::{Serialize, Deserialize, json::Json};
 use mangle_rust_utils::default_error;
 
 mod singletons;
 
-use rocket_db_pools::sqlx::error::DatabaseError;
-use rocket_db_pools::sqlx::sqlite::SqliteError;
-use singletons::{Logins, Sessions};
-pub use singletons::{FAILED_LOGINS, SessionID};
+use singletons::Logins;
+pub use singletons::{FAILED_LOGINS, SessionID, Sessions};
 use crate::{log::*, AppConfig};
 
 use self::singletons::{session_id_to_string, PasswordHash, UsernameError};
 
 use super::*;
+use super::db::{DbError, retry_busy};
+use super::migrations::Migration;
 
 use rocket_db_pools::{Database, Connection};
 use rocket_db_pools::sqlx::{self, Row};
@@ -26,6 +29,15 @@ use rocket_db_pools::sqlx::{self, Row};
 pub struct Credentials(sqlx::SqlitePool);
 
 
+pub const MIGRATIONS: &[Migration] = &[
+	Migration {
+		version: 1,
+		name: "create password users",
+		sql: include_str!("migrations/0001_create_password_users.sql")
+	}
+];
+
+
 pub struct AuthenticatedUser {
 	pub username: String
 }
@@ -72,9 +84,83 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
 }
 
 
+/// An authenticated user listed in the `admins` config
+pub struct AdminUser {
+	pub username: String
+}
+
+
+#[async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+	type Error = ();
+
+	async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self,Self::Error> {
+		let user = match request.guard::<AuthenticatedUser>().await {
+			Outcome::Success(x) => x,
+			Outcome::Failure(x) => return Outcome::Failure(x),
+			Outcome::Forward(x) => return Outcome::Forward(x)
+		};
+
+		let config: &AppConfig = request.rocket().state().unwrap();
+
+		if config.admins.contains(&user.username) {
+			Outcome::Success(Self {
+				username: user.username
+			})
+		} else {
+			request.local_cache(|| "User is not an admin".to_string());
+			Outcome::Failure((Status::Forbidden, ()))
+		}
+	}
+}
+
+
+/// An authenticated user listed in the `moderators` or `admins` config
+pub struct Moderator {
+	pub username: String
+}
+
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Moderator {
+	type Error = ();
+
+	async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self,Self::Error> {
+		let user = match request.guard::<AuthenticatedUser>().await {
+			Outcome::Success(x) => x,
+			Outcome::Failure(x) => return Outcome::Failure(x),
+			Outcome::Forward(x) => return Outcome::Forward(x)
+		};
+
+		let config: &AppConfig = request.rocket().state().unwrap();
+
+		if config.moderators.contains(&user.username) || config.admins.contains(&user.username) {
+			Outcome::Success(Self {
+				username: user.username
+			})
+		} else {
+			request.local_cache(|| "User is not a moderator".to_string());
+			Outcome::Failure((Status::Forbidden, ()))
+		}
+	}
+}
+
+
+/// Checks whether a password user with the given name exists
+pub async fn user_exists(username: &str, credentials: &mut Connection<Credentials>) -> Result<bool, DbError> {
+	retry_busy!(
+		sqlx::query("SELECT 1 FROM PasswordUsers WHERE Username = ?")
+			.bind(username)
+			.fetch_optional(&mut **credentials)
+	).map(|row| row.is_some())
+}
+
+
+/// Cheap to clone, so that WebSocket handlers can share it with Rocket
+#[derive(Clone)]
 pub struct AuthState {
-	pub logins: Logins,
-	pub sessions: Sessions,
+	pub logins: Arc<Logins>,
+	pub sessions: Arc<Sessions>,
 }
 
 
@@ -83,12 +169,32 @@ impl AuthState {
 		self.logins.prune_expired();
 		self.sessions.prune_expired();
 	}
+
+	/// Finds who a session key belongs to, for clients that cannot send the session header
+	pub fn authenticate(&self, session_key: &str) -> Option<AuthenticatedUser> {
+		let session_id: SessionID = session_key.chars().collect::<Vec<char>>().try_into().ok()?;
+
+		self.sessions
+			.get_session_owner(&session_id)
+			.map(|username| AuthenticatedUser { username })
+	}
+
+	/// Creates a new session for the given user and describes it for the client
+	fn start_session(&self, username: String) -> SessionInfo {
+		let session_id = self.sessions.create_session(username);
+
+		SessionInfo {
+			session_key: session_id_to_string(session_id),
+			expires_at: (UNIX_EPOCH.elapsed().unwrap() + self.sessions.max_session_duration).as_secs(),
+			renewals_left: self.sessions.max_renew_count()
+		}
+	}
 }
 
 
 pub(crate) fn make_auth_state(config: &AppConfig) -> AuthState {
 	AuthState {
-		logins: Logins::new(
+		logins: Arc::new(Logins::new(
 			Duration::from_secs(config.login_timeout as u64),
 			config.max_fails,
 			config.salt_len,
@@ -100,12 +206,12 @@ pub(crate) fn make_auth_state(config: &AppConfig) -> AuthState {
 				"parsing password regex"
 			),
 			config.password_hash_length
-		),
-		sessions: Sessions::new(
+		)),
+		sessions: Arc::new(Sessions::new(
 			Duration::from_secs(config.max_session_duration as u64),
 			Duration::from_secs(config.cleanup_interval as u64),
 			config.max_session_renewals
-		),
+		)),
 	}
 }
 
@@ -116,35 +222,150 @@ pub struct UserForm<'a> {
 	password: &'a str
 }
 
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UserJson {
+	username: String,
+	password: String
+}
+
+
+/// Describes a freshly started session
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SessionInfo {
+	session_key: String,
+	/// Unix time in seconds
+	expires_at: u64,
+	renewals_left: u8
+}
+
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum AuthErrorCode {
+	LockedOut,
+	InvalidCredentials,
+	InvalidUsername,
+	InvalidPassword,
+	UsernameInUse,
+	ServerBusy,
+	InternalError
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AuthErrorBody {
+	error: AuthErrorCode,
+	message: String
+}
+
+
+/// The response of the login and sign up endpoints
+///
+/// Clients that prefer JSON in their Accept header receive JSON bodies,
+/// everyone else receives the plain text bodies older Bola clients expect
+pub enum AuthResponse {
+	Session(SessionInfo),
+	Error(Status, AuthErrorCode, String)
+}
+
+
+impl AuthResponse {
+	fn error(status: Status, code: AuthErrorCode, message: impl Into<String>) -> Self {
+		Self::Error(status, code, message.into())
+	}
+
+	fn bug() -> Self {
+		Self::error(Status::InternalServerError, AuthErrorCode::InternalError, BUG_MESSAGE)
+	}
+
+	/// Wraps a generic response, such as the ones `DbError::into_response` produces
+	fn from_response((status, message): Response) -> Self {
+		let code = if status == Status::ServiceUnavailable {
+			AuthErrorCode::ServerBusy
+		} else {
+			AuthErrorCode::InternalError
+		};
+
+		Self::Error(status, code, message)
+	}
+
+	/// The only response a failed login may produce, whether or not the user exists
+	fn invalid_credentials() -> Self {
+		Self::error(Status::Unauthorized, AuthErrorCode::InvalidCredentials, "Username or password is incorrect")
+	}
+}
+
+
+impl<'r> Responder<'r, 'static> for AuthResponse {
+	fn respond_to(self, request: &'r rocket::Request<'_>) -> response::Result<'static> {
+		let wants_json = request
+			.accept()
+			.map(|accept| accept.preferred().is_json())
+			.unwrap_or(false);
+
+		match self {
+			Self::Session(info) if wants_json => Json(info).respond_to(request),
+			Self::Session(info) => info.session_key.respond_to(request),
+			Self::Error(status, error, message) if wants_json => (status, Json(AuthErrorBody { error, message })).respond_to(request),
+			Self::Error(status, _, message) => (status, message).respond_to(request)
+		}
+	}
+}
+
+
 /// Try to start a session with a username and password
 ///
 /// If the user has already opened one and it has not expired, it will be returned
-#[rocket::post("/login", data = "<form>")]
-pub(crate) async fn get_session_with_password<'a>(form: Form<UserForm<'a>>, mut credentials: Connection<Credentials>, auth: &State<AuthState>) -> Response {
+#[rocket::post("/login", data = "<form>", rank = 2)]
+pub(crate) async fn get_session_with_password<'a>(form: Form<UserForm<'a>>, credentials: Connection<Credentials>, auth: &State<AuthState>) -> AuthResponse {
+	let form = form.into_inner();
+	login(form.username, form.password, credentials, auth).await
+}
+
+
+/// Same as `get_session_with_password`, but for JSON bodies
+#[rocket::post("/login", format = "json", data = "<json>")]
+pub(crate) async fn get_session_with_json(json: Json<UserJson>, credentials: Connection<Credentials>, auth: &State<AuthState>) -> AuthResponse {
+	login(&json.username, &json.password, credentials, auth).await
+}
+
+
+async fn login(username: &str, password: &str, mut credentials: Connection<Credentials>, auth: &AuthState) -> AuthResponse {
 	auth.run_cleanups();
 
-	let form = form.into_inner();
-	let username = form.username;
 	let logins = &auth.logins;
 	
 	if let Some(remaining_time) = logins.is_user_locked_out(username) {
-		return make_response!(Status::Forbidden, format!("Locked out temporarily for {} secs", remaining_time.as_secs()))
+		return AuthResponse::error(
+			Status::Forbidden,
+			AuthErrorCode::LockedOut,
+			format!("Locked out temporarily for {} secs", remaining_time.as_secs())
+		)
 	}
 
-	let password = form.password;
-
-	let row = match sqlx::query("SELECT Salt, Hash FROM PasswordUsers WHERE Username = ?")
-		.bind(username.clone())
-		.fetch_optional(&mut *credentials).await {
+	let row = match retry_busy!(
+		sqlx::query("SELECT Salt, Hash FROM PasswordUsers WHERE Username = ?")
+			.bind(username)
+			.fetch_optional(&mut *credentials)
+	) {
 			Ok(Some(x)) => x,
-			Ok(None) => return make_response!(BadRequest, "User does not exist".into()),
-			Err(e) => {
-				default_error!(
-					e,
-					"querying credentials db"
-				);
-				return make_response!(BUG)
+			Ok(None) => {
+				// Spend as long as a real verification and count the failure, so that
+				// neither the response nor its timing reveals that the user is unknown
+				if let Err(e) = logins.verify_dummy_password(password) {
+					default_error!(
+						e,
+						"verifying dummy password"
+					);
+				}
+				logins.mark_failed_login(username.into());
+				return AuthResponse::invalid_credentials()
 			}
+			Err(e) => return AuthResponse::from_response(e.into_response("querying credentials db"))
 		};
 	
 	let salt: Vec<u8> = row.get_unchecked("Salt");
@@ -153,18 +374,18 @@ pub(crate) async fn get_session_with_password<'a>(form: Form<UserForm<'a>>, mut
 	match logins.verify_password(password, salt.as_slice(), hash.as_slice()) {
 		Ok(true) => {
 			logins.mark_succesful_login(username);
-			make_response!(Ok, session_id_to_string(auth.sessions.create_session(username.into())))
+			AuthResponse::Session(auth.start_session(username.into()))
 		},
 		Ok(false) => {
 			logins.mark_failed_login(username.into());
-			make_response!(Status::Unauthorized, "".into())
+			AuthResponse::invalid_credentials()
 		}
 		Err(e) => {
 			default_error!(
 				e,
 				"verifying password"
 			);
-			make_response!(BUG)
+			AuthResponse::bug()
 		}
 	}
 }
@@ -190,36 +411,47 @@ pub(crate) async fn remove_session<'a>(user: AuthenticatedUser, auth: &State<Aut
 
 
 /// Tries to create a new user, granted the creating user has appropriate abilities
-#[rocket::post("/sign_up", data = "<form>")]
-pub(crate) async fn make_user<'a>(form: Form<UserForm<'a>>, mut credentials: Connection<Credentials>, auth: &State<AuthState>) -> Response {
+#[rocket::post("/sign_up", data = "<form>", rank = 2)]
+pub(crate) async fn make_user<'a>(form: Form<UserForm<'a>>, credentials: Connection<Credentials>, auth: &State<AuthState>) -> AuthResponse {
+	let form = form.into_inner();
+	sign_up(form.username, form.password, credentials, auth).await
+}
+
+
+/// Same as `make_user`, but for JSON bodies
+#[rocket::post("/sign_up", format = "json", data = "<json>")]
+pub(crate) async fn make_user_with_json(json: Json<UserJson>, credentials: Connection<Credentials>, auth: &State<AuthState>) -> AuthResponse {
+	sign_up(&json.username, &json.password, credentials, auth).await
+}
+
+
+async fn sign_up(username: &str, password: &str, mut credentials: Connection<Credentials>, auth: &AuthState) -> AuthResponse {
 	auth.run_cleanups();
 	
-	let form = form.into_inner();
-	let username = form.username;
-	let password = form.password;
 	let logins = &auth.logins;
 
-	match logins.is_valid_username(&username) {
+	match logins.is_valid_username(username) {
 		Ok(()) => {}
-		Err(e) => return make_response!(
-			BadRequest,
+		Err(e) => return AuthResponse::error(
+			Status::BadRequest,
+			AuthErrorCode::InvalidUsername,
 			match e {
 				UsernameError::ContainsWhitespace => "Username contains whitespace",
 				UsernameError::Inappropriate => "Username is inappropriate",
 				UsernameError::TooShort => "Username is too short",
 				UsernameError::TooLong => "Username is too long",
 				UsernameError::IsNotAlphanumeric => "Username is not alphanumeric",
-			}.into()
+			}
 		),
 	}
 	if !logins.is_valid_password(password) {
-		return make_response!(BadRequest, "Password does not fit the requirements".into())
+		return AuthResponse::error(Status::BadRequest, AuthErrorCode::InvalidPassword, "Password does not fit the requirements")
 	}
 	
 	let _ = if let Some(x) = logins.reserve_username(username.into()) {
 		x
 	} else {
-		return make_response!(BadRequest, "Username already in use".into())
+		return AuthResponse::error(Status::BadRequest, AuthErrorCode::UsernameInUse, "Username already in use")
 	};
 
 	let PasswordHash {hash, salt} = match logins.hash_password(password) {
@@ -229,52 +461,23 @@ pub(crate) async fn make_user<'a>(form: Form<UserForm<'a>>, mut credentials: Con
 				e,
 				"hashing password"
 			);
-			return make_response!(BUG)
+			return AuthResponse::bug()
 		}
 	};
 
-	match sqlx::query("INSERT INTO PasswordUsers (Username, Salt, Hash) VALUES (?, ?, ?)")
-		.bind(username.clone())
-		.bind(salt)
-		.bind(hash)
-		.execute(&mut *credentials).await
-	{
+	match retry_busy!(
+		sqlx::query("INSERT INTO PasswordUsers (Username, Salt, Hash) VALUES (?, ?, ?)")
+			.bind(username)
+			.bind(&salt)
+			.bind(&hash)
+			.execute(&mut *credentials)
+	) {
 		Ok(_) => {}
-		Err(e) => return match e.as_database_error() {
-            Some(e) => {
-                let e: &SqliteError = e.downcast_ref();
-
-                let string;
-                let code = match e.code().unwrap() {
-                    std::borrow::Cow::Borrowed(x) => x,
-                    std::borrow::Cow::Owned(x) => {
-                        string = x;
-                        string.as_str()
-                    }
-                };
-
-                match code {
-                    "1555" => (Status::BadRequest, "Username is already in use".into()),
-                    _ => {
-                        default_error!(
-                            e,
-                            "inserting into PasswordUsers"
-                        );
-                        (Status::InternalServerError, crate::apps::BUG_MESSAGE.into())
-                    }
-                }
-            }
-            None => {
-                default_error!(
-                    e,
-                    "inserting into PasswordUsers"
-                );
-                (Status::InternalServerError, crate::apps::BUG_MESSAGE.into())
-            }
-        }
+		Err(DbError::UniqueViolation { .. }) => return AuthResponse::error(Status::BadRequest, AuthErrorCode::UsernameInUse, "Username is already in use"),
+		Err(e) => return AuthResponse::from_response(e.into_response("inserting into PasswordUsers"))
 	}
 
-	make_response!(Ok, session_id_to_string(auth.sessions.create_session(username.into())))
+	AuthResponse::Session(auth.start_session(username.into()))
 }
 
 // /// Tries to delete the user that is currently logged in
@@ -1,7 +1,4 @@
-use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
-use std::mem::replace;
-use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 use argon2::{Config as ArgonConfig, Error as ArgonError, hash_raw, verify_raw};
@@ -9,13 +6,17 @@ use rand::{CryptoRng, Rng, RngCore, thread_rng};
 use rand::distributions::Alphanumeric;
 use regex::Regex;
 use simple_logger::Logger;
-use std::sync::{Mutex, RwLock};
 use rustrict::CensorStr;
 
-use bimap::BiMap;
+use dashmap::{DashMap, DashSet};
+use dashmap::mapref::entry::Entry;
 
 pub static FAILED_LOGINS: Logger = Logger::new();
 
+/// Hashed at startup to verify unknown usernames against, so they cost as much as known ones
+const DUMMY_PASSWORD: &str = "mangle-dummy-password";
+const DUMMY_SALT_BYTE: u8 = 0x5a;
+
 
 struct FailedLoginAttempt {
 	running_count: u8,
@@ -37,7 +38,7 @@ pub struct UsernameReservation<'a> {
 
 impl<'a> Drop for UsernameReservation<'a> {
     fn drop(&mut self) {
-        self.logins.tmp_reserved_names.lock().unwrap().remove(&self.username);
+        self.logins.tmp_reserved_names.remove(&self.username);
     }
 }
 
@@ -53,52 +54,63 @@ struct SessionData {
 }
 
 
-impl Borrow<SessionID> for SessionData {
-    fn borrow(&self) -> &SessionID {
-        &self.id
-    }
+/// Tracks when the last cleanup happened without taking a lock
+struct CleanupTimer {
+	start: Instant,
+	interval: Duration,
+	/// Milliseconds since `start`, so that sub-second intervals keep their meaning
+	last_cleanup_millis: AtomicU64
 }
 
 
-impl std::hash::Hash for SessionData {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
-    }
-}
-
+impl CleanupTimer {
+	fn new(interval: Duration) -> Self {
+		Self {
+			start: Instant::now(),
+			interval,
+			last_cleanup_millis: AtomicU64::new(0)
+		}
+	}
 
-impl PartialEq for SessionData {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
-    }
-}
+	/// Returns true if a cleanup is due, in which case the caller is the only one that should perform it
+	fn try_claim(&self) -> bool {
+		let now = self.start.elapsed().as_millis() as u64;
+		let last = self.last_cleanup_millis.load(Ordering::Acquire);
 
+		// Another thread may have claimed a later time since `now` was read
+		if now.saturating_sub(last) < self.interval.as_millis() as u64 {
+			return false
+		}
 
-impl Eq for SessionData {}
+		self.last_cleanup_millis
+			.compare_exchange(last, now, Ordering::AcqRel, Ordering::Acquire)
+			.is_ok()
+	}
+}
 
 
 /// Manages user authentication and user creation
 pub struct Logins {
 	lockout_time: Duration,
 	max_fails: u8,
-	failed_logins: RwLock<HashMap<String, FailedLoginAttempt>>,
+	failed_logins: DashMap<String, FailedLoginAttempt>,
 	argon2_config: ArgonConfig<'static>,
+	dummy_hash: PasswordHash,
 	salt_len: u8,
 	min_username_len: u8,
 	max_username_len: u8,
 	password_regex: Regex,
-	tmp_reserved_names: Mutex<HashSet<String>>,
-	cleanup_interval: Duration,
-	last_cleanup_time: RwLock<Instant>
+	tmp_reserved_names: DashSet<String>,
+	cleanup_timer: CleanupTimer
 }
 
 
 /// Manages user sessions
 pub struct Sessions {
-	user_session_map: RwLock<BiMap<String, SessionData>>,
+	user_sessions: DashMap<String, SessionData>,
+	session_owners: DashMap<SessionID, String>,
 	pub(crate) max_session_duration: Duration,
-	cleanup_interval: Duration,
-	last_cleanup_time: RwLock<Instant>,
+	cleanup_timer: CleanupTimer,
 	max_renew_count: u8
 }
 
@@ -131,41 +143,39 @@ impl Logins {
 		let mut argon2_config: ArgonConfig = Default::default();
 		argon2_config.hash_length = hash_length as u32;
 
+		let dummy_salt = vec![DUMMY_SALT_BYTE; salt_len as usize];
+		let dummy_hash = PasswordHash {
+			hash: hash_raw(DUMMY_PASSWORD.as_bytes(), dummy_salt.as_slice(), &argon2_config)
+				.expect("Could not hash the dummy password"),
+			salt: dummy_salt
+		};
+
 		Self {
 			lockout_time,
 			max_fails,
 			failed_logins: Default::default(),
 			argon2_config,
+			dummy_hash,
 			salt_len,
 			min_username_len,
 			max_username_len,
 			password_regex,
-			cleanup_interval,
 			tmp_reserved_names: Default::default(),
-			last_cleanup_time: RwLock::new(Instant::now())
+			cleanup_timer: CleanupTimer::new(cleanup_interval)
 		}
 	}
 
 	/// Remove failed login attempts that are expired
 	pub fn prune_expired(&self) {
-		if self.last_cleanup_time.read().unwrap().elapsed() < self.cleanup_interval {
+		if !self.cleanup_timer.try_claim() {
 			return
 		}
 
-		*self.last_cleanup_time.write().unwrap() = Instant::now();
-
-		let mut writer = self.failed_logins.write().unwrap();
-		let old_fails = replace(writer.deref_mut(), HashMap::new());
-		
-		for (username, fail) in old_fails {
-			if fail.time.elapsed() < self.lockout_time {
-				writer.insert(username, fail);
-			}
-		}
+		self.failed_logins.retain(|_, fail| fail.time.elapsed() < self.lockout_time);
 	}
 
 	pub fn is_user_locked_out(&self, username: &str) -> Option<Duration> {
-		if let Some(attempt) = self.failed_logins.read().unwrap().get(username) {
+		if let Some(attempt) = self.failed_logins.get(username) {
 			let elapsed_time = attempt.time.elapsed();
 
 			if attempt.running_count >= self.max_fails && elapsed_time < self.lockout_time {
@@ -179,32 +189,28 @@ impl Logins {
 	}
 
 	pub fn mark_failed_login(&self, username: String) {
-		let mut writer = self.failed_logins.write().unwrap();
-
-		let attempt = match writer.remove(&username) {
-			Some(mut attempt) => {
+		match self.failed_logins.entry(username) {
+			Entry::Occupied(mut entry) => {
+				let attempt = entry.get_mut();
 				if attempt.running_count >= self.max_fails {
 					attempt.running_count = 1;
 				} else {
 					attempt.running_count += 1;
 				}
 				attempt.time = Instant::now();
-				attempt
 			}
-			None => FailedLoginAttempt { running_count: 1, time: Instant::now() }
-		};
-
-		writer.insert(username, attempt);
+			Entry::Vacant(entry) => {
+				entry.insert(FailedLoginAttempt { running_count: 1, time: Instant::now() });
+			}
+		}
 	}
 
 	pub fn mark_succesful_login(&self, username: &str) {
-		self.failed_logins.write().unwrap().remove(username);
+		self.failed_logins.remove(username);
 	}
 
 	pub fn reserve_username(&self, username: String) -> Option<UsernameReservation> {
-		let mut lock = self.tmp_reserved_names.lock().unwrap();
-
-		if !lock.insert(username.clone()) {
+		if !self.tmp_reserved_names.insert(username.clone()) {
 			return None
 		}
 
@@ -254,6 +260,14 @@ impl Logins {
 		)
 	}
 
+	/// Does the same work as `verify_password` for a user that does not exist
+	///
+	/// The outcome is deliberately discarded, the caller must treat the login as failed
+	pub fn verify_dummy_password(&self, password: &str) -> Result<(), ArgonError> {
+		self.verify_password(password, self.dummy_hash.salt.as_slice(), self.dummy_hash.hash.as_slice())
+			.map(|_| ())
+	}
+
 	// pub fn delete_user(&self, username: String) -> Option<UserDeletionPromise> {
 	// 	if self.user_cred_map.read().unwrap().contains_key(&username) {
 	// 		Some(UserDeletionPromise {
@@ -292,82 +306,88 @@ impl Sessions {
 	/// Creates a Sessions instance that has a separate task that performs occasional cleanups
 	pub fn new(max_session_duration: Duration, cleanup_interval: Duration, max_renew_count: u8) -> Self {
 		Self {
-			user_session_map: Default::default(),
-			cleanup_interval,
+			user_sessions: Default::default(),
+			session_owners: Default::default(),
 			max_session_duration,
-			last_cleanup_time: RwLock::new(Instant::now()),
+			cleanup_timer: CleanupTimer::new(cleanup_interval),
 			max_renew_count
 		}
 	}
 
 	// pub fn has_session(&self, username: &str) -> bool {
-	// 	self.user_session_map.read().unwrap().contains_left(username)
+	// 	self.user_sessions.contains_key(username)
 	// }
 
 	/// Create a new session for the given user, replacing an existing one if it exists
 	///
 	/// Does not check if the user has been authenticated
 	pub fn create_session(&self, username: String) -> SessionID {
-		let mut writer = self.user_session_map.write().unwrap();
 		let mut rand_gen = thread_rng();
-		let mut session_id = make_session_id(&mut rand_gen);
 
-		let mut session_data = SessionData {
-			id: session_id.clone(),
+		// Claim an unused id first so that it can never be handed to two users
+		let session_id = loop {
+			let session_id = make_session_id(&mut rand_gen);
+
+			if let Entry::Vacant(entry) = self.session_owners.entry(session_id) {
+				entry.insert(username.clone());
+				break session_id
+			}
+		};
+
+		let session_data = SessionData {
+			id: session_id,
 			creation_time: Instant::now(),
 			renew_count: 0
 		};
 
-		while writer.contains_right(&session_data) {
-			session_id = make_session_id(&mut rand_gen);
-			session_data.id = session_id.clone();
+		if let Some(old_data) = self.user_sessions.insert(username, session_data) {
+			self.session_owners.remove(&old_data.id);
 		}
 
-		writer.insert(username, session_data);
-
 		session_id
 	}
 
 	pub fn renew_session(&self, username: &str) -> Option<u8> {
-		let mut writer = self.user_session_map.write().unwrap();
-		let (username, mut data) = writer.remove_by_left(username)?;
+		let mut data = self.user_sessions.get_mut(username)?;
 		
 		if data.renew_count >= self.max_renew_count {
+			drop(data);
+			self.remove_session(username);
 			None
 		} else {
 			data.renew_count += 1;
 			data.creation_time = Instant::now();
-			let renew_count = data.renew_count;
-			writer.insert(username, data);
-			Some(self.max_renew_count - renew_count)
+			Some(self.max_renew_count - data.renew_count)
 		}
 	}
 
 	pub fn remove_session(&self, username: &str) {
-		self.user_session_map.write().unwrap().remove_by_left(username);
+		if let Some((_, data)) = self.user_sessions.remove(username) {
+			self.session_owners.remove(&data.id);
+		}
 	}
 
 	/// Remove expired sessions
 	pub fn prune_expired(&self) {
-		if self.last_cleanup_time.read().unwrap().elapsed() < self.cleanup_interval {
+		if !self.cleanup_timer.try_claim() {
 			return
 		}
 
-		*self.last_cleanup_time.write().unwrap() = Instant::now();
-
-		let mut writer = self.user_session_map.write().unwrap();
-		let old_sessions = replace(writer.deref_mut(), BiMap::new());
-
-		for (username, session_data) in old_sessions {
+		self.user_sessions.retain(|_, session_data| {
 			if session_data.creation_time.elapsed() >= self.max_session_duration {
-				writer.remove_by_right(&session_data);
+				self.session_owners.remove(&session_data.id);
+				false
 			} else {
-				writer.insert(username, session_data);
+				true
 			}
-		}
+		});
+	}
+
+	pub fn max_renew_count(&self) -> u8 {
+		self.max_renew_count
 	}
 
 	pub fn get_session_owner(&self, id: &SessionID) -> Option<String> {
-		self.user_session_map.read().unwrap().get_by_right(id).cloned()
+		self.session_owners.get(id).map(|owner| owner.clone())
 	}
-}
\ No newline at end of file
+}
\ No newline at end of file
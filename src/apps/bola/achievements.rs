@@ -0,0 +1,246 @@
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use once_cell::sync::Lazy;
+use rocket::serde::json::{from_str, to_string};
+use rocket::serde::{Deserialize, Serialize};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Row};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{BolaData, MAX_DIFFICULTY, profiles};
+use crate::apps::db::{DbError, retry_busy};
+use crate::apps::{Response, make_response};
+use crate::log::*;
+use crate::ws::{WsConnection, WsContext, WsHub, WsRoute, require_user};
+
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+enum Condition {
+    /// Best run on a difficulty reached at least this many levels
+    Levels { difficulty: u8, levels: u16 },
+    TournamentWins { count: u32 },
+    /// Longest streak of days with a finished run
+    Streak { days: u32 },
+    All { conditions: Vec<Condition> }
+}
+
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Achievement {
+    id: String,
+    name: String,
+    description: String,
+    condition: Condition
+}
+
+
+static ACHIEVEMENTS: Lazy<Vec<Achievement>> = Lazy::new(|| {
+    from_str(include_str!("achievements.json")).expect("achievements.json is invalid")
+});
+
+
+/// What conditions are checked against
+struct Progress {
+    best_levels: [u16; MAX_DIFFICULTY as usize],
+    tournament_wins: u32,
+    longest_streak: u32
+}
+
+
+impl Condition {
+    fn is_met(&self, progress: &Progress) -> bool {
+        match self {
+            Self::Levels { difficulty, levels } => progress.best_levels
+                .get((*difficulty as usize).wrapping_sub(1))
+                .map_or(false, |best| best >= levels),
+            Self::TournamentWins { count } => progress.tournament_wins >= *count,
+            Self::Streak { days } => progress.longest_streak >= *days,
+            Self::All { conditions } => conditions.iter().all(|condition| condition.is_met(progress))
+        }
+    }
+}
+
+
+async fn load_progress(username: &str, bola_data: &mut Connection<BolaData>) -> Result<Progress, DbError> {
+    let mut progress = Progress {
+        best_levels: [0; MAX_DIFFICULTY as usize],
+        tournament_wins: 0,
+        longest_streak: profiles::streaks(username, bola_data).await?.1
+    };
+
+    let rows = retry_busy!(
+        sqlx::query("SELECT Difficulty, Levels FROM EndlessLeaderboard WHERE Username = ?")
+            .bind(username)
+            .fetch_all(&mut **bola_data)
+    )?;
+
+    for row in rows {
+        if let Some(best) = progress.best_levels.get_mut((row.get_unchecked::<u8, _>("Difficulty") as usize).wrapping_sub(1)) {
+            *best = row.get_unchecked("Levels");
+        }
+    }
+
+    let row = retry_busy!(
+        sqlx::query("SELECT COUNT(*) FROM TournamentWinners WHERE Username = ?")
+            .bind(username)
+            .fetch_one(&mut **bola_data)
+    )?;
+    progress.tournament_wins = row.get_unchecked("COUNT(*)");
+
+    Ok(progress)
+}
+
+
+/// Hub topic of every achievement unlocked by any player
+/// Where a player's own unlocks are pushed
+fn topic(username: &str) -> String {
+    format!("bola/achievements/{username}")
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Unlock<'a> {
+    username: &'a str,
+    achievement: &'a str,
+    name: &'a str,
+    unlocked_at: f64
+}
+
+
+/// Records every achievement the player now qualifies for, and announces the new ones
+async fn unlock_achievements(username: &str, hub: &WsHub, bola_data: &mut Connection<BolaData>) -> Result<(), DbError> {
+    let progress = load_progress(username, bola_data).await?;
+    let unlocked_at = UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+    let mut unlocked = Vec::new();
+
+    for achievement in ACHIEVEMENTS.iter().filter(|achievement| achievement.condition.is_met(&progress)) {
+        let result = retry_busy!(
+            sqlx::query("INSERT OR IGNORE INTO UserAchievements (Username, AchievementID, UnlockedAt) VALUES (?, ?, ?)")
+                .bind(username)
+                .bind(&achievement.id)
+                .bind(unlocked_at)
+                .execute(&mut **bola_data)
+        )?;
+
+        if result.rows_affected() > 0 {
+            unlocked.push(achievement);
+        }
+    }
+
+    for achievement in unlocked {
+        hub.publish(&topic(username), Message::Text(to_string(&Unlock {
+            username,
+            achievement: &achievement.id,
+            name: &achievement.name,
+            unlocked_at
+        }).unwrap()));
+    }
+
+    Ok(())
+}
+
+
+/// Unlocks achievements after the player's progress changed
+///
+/// Failing to do so does not undo the progress, so errors are only logged
+pub(super) async fn evaluate(username: &str, hub: &WsHub, bola_data: &mut Connection<BolaData>) {
+    if let Err(e) = unlock_achievements(username, hub, bola_data).await {
+        error!("{e} while evaluating achievements for {username}");
+    }
+}
+
+
+/// Lists the definitions of every achievement, whether or not anyone unlocked it.
+/// What a player unlocked is listed by `get_player_achievements`
+#[rocket::get("/achievements")]
+pub fn get_achievements() -> String {
+    to_string(&*ACHIEVEMENTS).unwrap()
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct UserAchievement {
+    achievement: String,
+    unlocked_at: f64
+}
+
+
+/// Lists the achievements a player unlocked, from the first one. Private players have none to show
+#[rocket::get("/players/<username>/achievements")]
+pub async fn get_player_achievements(username: &str, mut bola_data: Connection<BolaData>) -> Response {
+    match profiles::is_private(username, &mut bola_data).await {
+        Ok(true) => return make_response!(NotFound, "Player does not exist".into()),
+        Ok(false) => {}
+        Err(e) => return e.into_response("reading PlayerSettings")
+    }
+
+    let rows = match retry_busy!(
+        sqlx::query("SELECT AchievementID, UnlockedAt FROM UserAchievements WHERE Username = ? ORDER BY UnlockedAt, AchievementID")
+            .bind(username)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading from UserAchievements")
+    };
+
+    let achievements: Vec<_> = rows
+        .iter()
+        .map(|row| UserAchievement {
+            achievement: row.get_unchecked("AchievementID"),
+            unlocked_at: row.get_unchecked("UnlockedAt")
+        })
+        .collect();
+
+    make_response!(Ok, to_string(&achievements).unwrap())
+}
+
+
+/// Pushes achievements to the user who unlocked them. Clients that did not authenticate during the handshake must
+/// send their session key first
+pub(super) fn ws_route(ctx: Arc<WsContext>) -> WsRoute {
+    WsRoute::new("/achievements", move |connection| accept_achievements_ws(connection, ctx.clone()))
+}
+
+
+async fn accept_achievements_ws(connection: WsConnection, ctx: Arc<WsContext>) {
+    let WsConnection { mut stream, user, .. } = connection;
+
+    if let Some(user) = require_user(&mut stream, user, &ctx.auth).await {
+        ctx.hub.add_ws(stream, &[&topic(&user.username)]);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn difficulties(condition: &Condition) -> Vec<u8> {
+        match condition {
+            Condition::Levels { difficulty, .. } => vec![*difficulty],
+            Condition::All { conditions } => conditions.iter().flat_map(difficulties).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    #[test]
+    fn achievements_json_is_valid() {
+        let mut ids = HashSet::new();
+
+        for achievement in ACHIEVEMENTS.iter() {
+            assert!(ids.insert(&achievement.id), "{} is defined twice", achievement.id);
+
+            for difficulty in difficulties(&achievement.condition) {
+                assert!((1..=MAX_DIFFICULTY).contains(&difficulty), "{} has unknown difficulty {difficulty}", achievement.id);
+            }
+        }
+
+        assert!(!ids.is_empty());
+    }
+}
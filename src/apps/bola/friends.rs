@@ -0,0 +1,256 @@
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use rocket::State;
+use rocket::http::Status;
+use rocket::serde::json::to_string;
+use rocket::serde::Serialize;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Row, Sqlite};
+use rocket_db_pools::sqlx::query::Query;
+use rocket_db_pools::sqlx::sqlite::SqliteArguments;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{BolaData, profiles};
+use crate::apps::auth::{AuthenticatedUser, Credentials, user_exists};
+use crate::apps::db::{DbError, retry_busy};
+use crate::apps::{Response, make_response};
+use crate::log::*;
+use crate::ws::{WsConnection, WsContext, WsHub, WsRoute, require_user};
+
+/// Restricts to a player and their accepted friends. Must be used where a Username column is in scope.
+/// Binds are added by `bind_friends`
+pub(super) const FRIENDS_OF: &str = "Username IN (
+        SELECT Addressee FROM Friendships WHERE Requester = ? AND Status = 'accepted'
+        UNION SELECT Requester FROM Friendships WHERE Addressee = ? AND Status = 'accepted'
+        UNION SELECT ?
+    )";
+
+
+pub(super) fn bind_friends<'q>(query: Query<'q, Sqlite, SqliteArguments<'q>>, username: &str) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    query
+        .bind(username.to_string())
+        .bind(username.to_string())
+        .bind(username.to_string())
+}
+
+
+/// Picks whose friends a listing is limited to, which needs the viewer to be logged in
+pub(super) fn friends_filter(friends: Option<bool>, viewer: Option<AuthenticatedUser>) -> Result<Option<String>, Response> {
+    match (friends.unwrap_or(false), viewer) {
+        (false, _) => Ok(None),
+        (true, Some(viewer)) => Ok(Some(viewer.username)),
+        (true, None) => Err(make_response!(Status::Unauthorized, "Log in to see friends".into()))
+    }
+}
+
+
+/// Hub topic of the notifications of one user
+fn topic(username: &str) -> String {
+    format!("bola/friends/{username}")
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub(super) enum Notification<'a> {
+    FriendRequest { from: &'a str },
+    FriendAccepted { by: &'a str },
+    LeaderboardEntry { username: &'a str, difficulty: u8, levels: u16 },
+    TournamentWin { username: &'a str, week: u32 }
+}
+
+
+fn notify(hub: &WsHub, username: &str, notification: &Notification<'_>) {
+    hub.publish(&topic(username), Message::Text(to_string(notification).unwrap()));
+}
+
+
+/// Tells everyone who is friends with `username` about their activity, unless they are private
+pub(super) async fn notify_friends(username: &str, notification: Notification<'_>, hub: &WsHub, bola_data: &mut Connection<BolaData>) {
+    match profiles::is_private(username, bola_data).await {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(e) => {
+            error!("{e} while checking if {username} is private");
+            return
+        }
+    }
+
+    let rows = match retry_busy!(
+        sqlx::query(
+            "SELECT Addressee AS Friend FROM Friendships WHERE Requester = ? AND Status = 'accepted'
+            UNION SELECT Requester FROM Friendships WHERE Addressee = ? AND Status = 'accepted'"
+        )
+            .bind(username)
+            .bind(username)
+            .fetch_all(&mut **bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{e} while finding friends of {username} to notify");
+            return
+        }
+    };
+
+    for row in rows {
+        notify(hub, row.get_unchecked("Friend"), &notification);
+    }
+}
+
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct FriendList {
+    friends: Vec<String>,
+    /// Requests sent to the user
+    incoming: Vec<String>,
+    /// Requests the user sent
+    outgoing: Vec<String>
+}
+
+
+#[rocket::get("/friends")]
+pub async fn list_friends(user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
+    let rows = match retry_busy!(
+        sqlx::query("SELECT Requester, Addressee, Status FROM Friendships WHERE Requester = ? OR Addressee = ? ORDER BY CreatedAt")
+            .bind(&user.username)
+            .bind(&user.username)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading from Friendships")
+    };
+
+    let mut list = FriendList::default();
+
+    for row in rows {
+        let requester: String = row.get_unchecked("Requester");
+        let addressee: String = row.get_unchecked("Addressee");
+        let accepted = row.get_unchecked::<&str, _>("Status") == "accepted";
+
+        match (requester == user.username, accepted) {
+            (true, true) => list.friends.push(addressee),
+            (false, true) => list.friends.push(requester),
+            (true, false) => list.outgoing.push(addressee),
+            (false, false) => list.incoming.push(requester)
+        }
+    }
+
+    make_response!(Ok, to_string(&list).unwrap())
+}
+
+
+/// Sends a friend request, or accepts the one `username` already sent
+#[rocket::post("/friends/<username>")]
+pub async fn send_friend_request(username: &str, user: AuthenticatedUser, hub: &State<Arc<WsHub>>, mut credentials: Connection<Credentials>, mut bola_data: Connection<BolaData>) -> Response {
+    if username == user.username {
+        return make_response!(BadRequest, "Cannot befriend yourself".into())
+    }
+
+    match user_exists(username, &mut credentials).await {
+        Ok(true) => {}
+        Ok(false) => return make_response!(NotFound, "Player does not exist".into()),
+        Err(e) => return e.into_response("checking PasswordUsers")
+    }
+
+    match retry_busy!(
+        sqlx::query("UPDATE Friendships SET Status = 'accepted' WHERE Requester = ? AND Addressee = ? AND Status = 'pending'")
+            .bind(username)
+            .bind(&user.username)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() > 0 => {
+            notify(hub, username, &Notification::FriendAccepted { by: &user.username });
+            return make_response!(Ok, "Friend request was accepted".into())
+        }
+        Ok(_) => {}
+        Err(e) => return e.into_response("updating Friendships")
+    }
+
+    // Also conflicts with a friendship accepted the other way around, which the primary key cannot see
+    match retry_busy!(
+        sqlx::query(
+            "INSERT INTO Friendships (Requester, Addressee, Status, CreatedAt) SELECT ?1, ?2, 'pending', ?3
+            WHERE NOT EXISTS (SELECT 1 FROM Friendships WHERE Requester = ?2 AND Addressee = ?1)"
+        )
+            .bind(&user.username)
+            .bind(username)
+            .bind(UNIX_EPOCH.elapsed().unwrap().as_secs_f64())
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() > 0 => {
+            notify(hub, username, &Notification::FriendRequest { from: &user.username });
+            make_response!(Ok, "Friend request was sent".into())
+        }
+        Ok(_) | Err(DbError::UniqueViolation { .. }) => make_response!(BadRequest, "Player is already a friend or has a pending request".into()),
+        Err(e) => e.into_response("inserting into Friendships")
+    }
+}
+
+
+#[rocket::post("/friends/<username>/accept")]
+pub async fn accept_friend_request(username: &str, user: AuthenticatedUser, hub: &State<Arc<WsHub>>, mut bola_data: Connection<BolaData>) -> Response {
+    match retry_busy!(
+        sqlx::query("UPDATE Friendships SET Status = 'accepted' WHERE Requester = ? AND Addressee = ? AND Status = 'pending'")
+            .bind(username)
+            .bind(&user.username)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() > 0 => {
+            notify(hub, username, &Notification::FriendAccepted { by: &user.username });
+            make_response!(Ok, "Friend request was accepted".into())
+        }
+        Ok(_) => make_response!(NotFound, "No friend request from this player".into()),
+        Err(e) => e.into_response("updating Friendships")
+    }
+}
+
+
+#[rocket::post("/friends/<username>/decline")]
+pub async fn decline_friend_request(username: &str, user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
+    match retry_busy!(
+        sqlx::query("DELETE FROM Friendships WHERE Requester = ? AND Addressee = ? AND Status = 'pending'")
+            .bind(username)
+            .bind(&user.username)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() > 0 => make_response!(Ok, "Friend request was declined".into()),
+        Ok(_) => make_response!(NotFound, "No friend request from this player".into()),
+        Err(e) => e.into_response("deleting from Friendships")
+    }
+}
+
+
+/// Removes a friend, or withdraws a request sent to them
+#[rocket::delete("/friends/<username>")]
+pub async fn remove_friend(username: &str, user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
+    match retry_busy!(
+        sqlx::query(
+            "DELETE FROM Friendships WHERE (Requester = ?1 AND Addressee = ?2)
+            OR (Requester = ?2 AND Addressee = ?1 AND Status = 'accepted')"
+        )
+            .bind(&user.username)
+            .bind(username)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() > 0 => make_response!(Ok, "Friend was removed".into()),
+        Ok(_) => make_response!(NotFound, "Player is not a friend".into()),
+        Err(e) => e.into_response("deleting from Friendships")
+    }
+}
+
+
+/// Pushes notifications to a user. Clients that did not authenticate during the handshake must send their session key first
+pub(super) fn ws_route(ctx: Arc<WsContext>) -> WsRoute {
+    WsRoute::new("/friends", move |connection| accept_friends_ws(connection, ctx.clone()))
+}
+
+
+async fn accept_friends_ws(connection: WsConnection, ctx: Arc<WsContext>) {
+    let WsConnection { mut stream, user, .. } = connection;
+
+    if let Some(user) = require_user(&mut stream, user, &ctx.auth).await {
+        ctx.hub.add_ws(stream, &[&topic(&user.username)]);
+    }
+}
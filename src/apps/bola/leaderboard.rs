@@ -0,0 +1,461 @@
+use std::time::UNIX_EPOCH;
+
+use rocket::{FromFormField, State};
+use rocket::serde::json::to_string;
+use rocket::serde::Serialize;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Row, Sqlite, SqliteConnection};
+use rocket_db_pools::sqlx::query::Query;
+use rocket_db_pools::sqlx::sqlite::{SqliteArguments, SqliteRow};
+
+use super::{BolaData, Difficulty};
+use super::friends::{FRIENDS_OF, bind_friends, friends_filter};
+use super::profiles::NOT_PRIVATE;
+use super::tournament::TournamentSchedule;
+use crate::apps::auth::AuthenticatedUser;
+use crate::apps::db::{DbError, retry_busy};
+use crate::apps::{Response, make_response};
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+/// How many entries are shown on each side of a player's rank
+const NEIGHBOR_COUNT: u8 = 2;
+
+/// Better entries come first. Ties are broken by who got there first, then by name so the order is total
+const RANK_ORDER: &str = "ORDER BY Levels DESC, Time ASC, Username ASC";
+const REVERSE_RANK_ORDER: &str = "ORDER BY Levels ASC, Time DESC, Username DESC";
+/// Matches entries ranked at or above a position. Binds are added by `bind_position`
+const AT_OR_ABOVE: &str = "(Levels > ? OR (Levels = ? AND (Time < ? OR (Time = ? AND Username <= ?))))";
+/// Matches entries ranked strictly below a position. Binds are added by `bind_position`
+const BELOW: &str = "(Levels < ? OR (Levels = ? AND (Time > ? OR (Time = ? AND Username > ?))))";
+
+/// Migration 0004 backfills daily periods with the same length
+pub(super) const SECS_PER_DAY: u64 = 3600 * 24;
+
+
+/// The span of time a leaderboard covers
+#[derive(FromFormField, Clone, Copy)]
+pub enum LeaderboardWindow {
+    /// Resets every day at midnight UTC
+    Daily,
+    /// Follows the tournament schedule, with the tournament's week as the period
+    Weekly,
+    #[field(value = "all_time")]
+    AllTime
+}
+
+
+impl LeaderboardWindow {
+    /// Windows that are kept in WindowedLeaderboard. All time entries live in EndlessLeaderboard
+    const MATERIALIZED: [Self; 2] = [Self::Daily, Self::Weekly];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+            Self::AllTime => "all_time"
+        }
+    }
+
+    /// The period a submission made right now falls into. There is no weekly period while no tournament is running
+    async fn current_period(&self, schedule: &TournamentSchedule, bola_data: &mut Connection<BolaData>) -> Result<Option<u32>, DbError> {
+        Ok(match self {
+            Self::Daily => Some((UNIX_EPOCH.elapsed().unwrap().as_secs() / SECS_PER_DAY) as u32),
+            Self::Weekly => schedule.current_event(bola_data).await?.map(|event| event.id),
+            Self::AllTime => Some(0)
+        })
+    }
+}
+
+
+/// A single leaderboard: one difficulty over one period of a window
+struct Board {
+    window: LeaderboardWindow,
+    period: u32,
+    difficulty: u8,
+    /// Limits the board to this player and their friends
+    friends_of: Option<String>
+}
+
+
+impl Board {
+    /// The table and filter selecting this board's visible entries, to follow `FROM`. Binds are added by `bind`
+    fn source(&self) -> String {
+        let source = match self.window {
+            LeaderboardWindow::AllTime => format!("EndlessLeaderboard WHERE Difficulty = ? AND Hidden = 0 AND {NOT_PRIVATE}"),
+            _ => format!("WindowedLeaderboard WHERE TimeWindow = ? AND Period = ? AND Difficulty = ? AND Hidden = 0 AND {NOT_PRIVATE}")
+        };
+
+        match self.friends_of {
+            Some(_) => format!("{source} AND {FRIENDS_OF}"),
+            None => source
+        }
+    }
+
+    fn bind<'q>(&self, query: Query<'q, Sqlite, SqliteArguments<'q>>) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+        let query = match self.window {
+            LeaderboardWindow::AllTime => query.bind(self.difficulty),
+            window => query
+                .bind(window.name())
+                .bind(self.period)
+                .bind(self.difficulty)
+        };
+
+        match &self.friends_of {
+            Some(username) => bind_friends(query, username),
+            None => query
+        }
+    }
+}
+
+
+/// Picks the board a request asks for, defaulting to all time and the current period of the window
+async fn resolve_board(difficulty: Difficulty, window: Option<LeaderboardWindow>, period: Option<u32>, friends: Option<bool>, viewer: Option<AuthenticatedUser>, schedule: &TournamentSchedule, bola_data: &mut Connection<BolaData>) -> Result<Board, Response> {
+    let friends_of = friends_filter(friends, viewer)?;
+    let window = window.unwrap_or(LeaderboardWindow::AllTime);
+    let period = match period {
+        Some(x) => x,
+        None => match window.current_period(schedule, bola_data).await {
+            Ok(Some(x)) => x,
+            Ok(None) => return Err(make_response!(NotFound, "No tournament is running".into())),
+            Err(e) => return Err(e.into_response("finding the current period"))
+        }
+    };
+
+    Ok(Board {
+        window,
+        period,
+        difficulty: difficulty.0,
+        friends_of
+    })
+}
+
+
+/// The daily and weekly periods that a run finishing now counts towards
+pub(super) async fn current_periods(schedule: &TournamentSchedule, bola_data: &mut Connection<BolaData>) -> Result<Vec<(LeaderboardWindow, u32)>, DbError> {
+    let mut periods = Vec::new();
+
+    for window in LeaderboardWindow::MATERIALIZED {
+        if let Some(period) = window.current_period(schedule, bola_data).await? {
+            periods.push((window, period));
+        }
+    }
+
+    Ok(periods)
+}
+
+
+/// Records a finished run on the leaderboards of the given periods, where it beats what the user has there
+pub(super) async fn record_windowed_entry(username: &str, difficulty: u8, levels: u16, time: f64, run_id: i64, periods: &[(LeaderboardWindow, u32)], conn: &mut SqliteConnection) -> Result<(), DbError> {
+    for (window, period) in periods {
+        sqlx::query(
+            "INSERT INTO WindowedLeaderboard (TimeWindow, Period, Username, Difficulty, Levels, Time, RunID) VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (TimeWindow, Period, Username, Difficulty) DO UPDATE
+            SET Levels = excluded.Levels, Time = excluded.Time, RunID = excluded.RunID
+            WHERE excluded.Levels > WindowedLeaderboard.Levels"
+        )
+            .bind(window.name())
+            .bind(period)
+            .bind(username)
+            .bind(difficulty)
+            .bind(levels)
+            .bind(time)
+            .bind(run_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+
+/// A place on the leaderboard, which also serves as a pagination cursor
+struct Position {
+    levels: u16,
+    time: f64,
+    username: String
+}
+
+
+impl Position {
+    fn parse_cursor(cursor: &str) -> Option<Self> {
+        let mut parts = cursor.splitn(3, ':');
+
+        Some(Self {
+            levels: parts.next()?.parse().ok()?,
+            time: parts.next()?.parse().ok()?,
+            username: parts.next()?.to_string()
+        })
+    }
+
+    fn to_cursor(&self) -> String {
+        format!("{}:{}:{}", self.levels, self.time, self.username)
+    }
+}
+
+
+fn bind_position<'q>(query: Query<'q, Sqlite, SqliteArguments<'q>>, position: &'q Position) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    query
+        .bind(position.levels)
+        .bind(position.levels)
+        .bind(position.time)
+        .bind(position.time)
+        .bind(&position.username)
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(super) struct RankedEntry {
+    pub(super) rank: u32,
+    pub(super) username: String,
+    pub(super) levels: u16,
+    pub(super) time: f64
+}
+
+
+impl RankedEntry {
+    fn from_row(row: &SqliteRow, rank: u32) -> Self {
+        Self {
+            rank,
+            username: row.get_unchecked("Username"),
+            levels: row.get_unchecked("Levels"),
+            time: row.get_unchecked("Time")
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            levels: self.levels,
+            time: self.time,
+            username: self.username.clone()
+        }
+    }
+}
+
+
+/// Counts how many entries are ranked at or above the given position, which is the rank of that position
+async fn rank_of(position: &Position, board: &Board, bola_data: &mut Connection<BolaData>) -> Result<u32, Response> {
+    let sql = format!("SELECT COUNT(*) FROM {} AND {AT_OR_ABOVE}", board.source());
+
+    match retry_busy!(
+        bind_position(board.bind(sqlx::query(&sql)), position)
+            .fetch_one(&mut **bola_data)
+    ) {
+        Ok(row) => Ok(row.get_unchecked("COUNT(*)")),
+        Err(e) => Err(e.into_response("ranking leaderboard entry"))
+    }
+}
+
+
+fn all_time_board(difficulty: u8) -> Board {
+    Board {
+        window: LeaderboardWindow::AllTime,
+        period: 0,
+        difficulty,
+        friends_of: None
+    }
+}
+
+
+/// Finds a player's visible entry on the all time leaderboard of a difficulty, with its rank
+pub(super) async fn all_time_entry(username: &str, difficulty: u8, conn: &mut SqliteConnection) -> Result<Option<RankedEntry>, DbError> {
+    let board = all_time_board(difficulty);
+    let position_sql = format!("SELECT Username, Levels, Time FROM {} AND Username = ?", board.source());
+
+    let position = match retry_busy!(
+        board.bind(sqlx::query(&position_sql))
+            .bind(username)
+            .fetch_optional(&mut *conn)
+    )? {
+        Some(row) => RankedEntry::from_row(&row, 0).position(),
+        None => return Ok(None)
+    };
+
+    let rank_sql = format!("SELECT COUNT(*) FROM {} AND {AT_OR_ABOVE}", board.source());
+    let row = retry_busy!(
+        bind_position(board.bind(sqlx::query(&rank_sql)), &position)
+            .fetch_one(&mut *conn)
+    )?;
+
+    Ok(Some(RankedEntry {
+        rank: row.get_unchecked("COUNT(*)"),
+        username: position.username,
+        levels: position.levels,
+        time: position.time
+    }))
+}
+
+
+/// Lists the all time leaderboard of a difficulty from the top, down to rank `limit` if given
+pub(super) async fn all_time_top(difficulty: u8, limit: Option<u32>, conn: &mut SqliteConnection) -> Result<Vec<RankedEntry>, DbError> {
+    let board = all_time_board(difficulty);
+    let sql = format!("SELECT Username, Levels, Time FROM {} {RANK_ORDER} LIMIT ?", board.source());
+
+    let rows = retry_busy!(
+        board.bind(sqlx::query(&sql))
+            // A negative limit is no limit in SQLite
+            .bind(limit.map(i64::from).unwrap_or(-1))
+            .fetch_all(&mut *conn)
+    )?;
+
+    Ok(rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| RankedEntry::from_row(row, i as u32 + 1))
+        .collect())
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LeaderboardPage {
+    entries: Vec<RankedEntry>,
+    /// Pass as `after` to get the next page. Missing on the last page
+    next: Option<String>
+}
+
+
+/// Lists the endless leaderboard of a difficulty from the top, a page at a time
+///
+/// `window` defaults to all time, and `period` to the current day or week of the window.
+/// `friends` limits it to the logged in user and their friends
+#[rocket::get("/leaderboard/endless?<difficulty>&<window>&<period>&<friends>&<limit>&<after>")]
+pub async fn get_leaderboard_page(difficulty: Difficulty, window: Option<LeaderboardWindow>, period: Option<u32>, friends: Option<bool>, limit: Option<u32>, after: Option<&str>, viewer: Option<AuthenticatedUser>, schedule: &State<TournamentSchedule>, mut bola_data: Connection<BolaData>) -> Response {
+    let board = match resolve_board(difficulty, window, period, friends, viewer, schedule, &mut bola_data).await {
+        Ok(x) => x,
+        Err(response) => return response
+    };
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let after = match after.map(Position::parse_cursor) {
+        Some(Some(x)) => Some(x),
+        Some(None) => return make_response!(BadRequest, "Invalid cursor".into()),
+        None => None
+    };
+
+    let rows = match &after {
+        Some(position) => {
+            let sql = format!("SELECT Username, Levels, Time FROM {} AND {BELOW} {RANK_ORDER} LIMIT ?", board.source());
+
+            retry_busy!(
+                bind_position(board.bind(sqlx::query(&sql)), position)
+                    .bind(limit)
+                    .fetch_all(&mut *bola_data)
+            )
+        }
+        None => {
+            let sql = format!("SELECT Username, Levels, Time FROM {} {RANK_ORDER} LIMIT ?", board.source());
+
+            retry_busy!(
+                board.bind(sqlx::query(&sql))
+                    .bind(limit)
+                    .fetch_all(&mut *bola_data)
+            )
+        }
+    };
+
+    let rows = match rows {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading page of leaderboard")
+    };
+
+    let first_rank = match &after {
+        Some(position) => match rank_of(position, &board, &mut bola_data).await {
+            Ok(x) => x + 1,
+            Err(response) => return response
+        },
+        None => 1
+    };
+
+    let entries: Vec<_> = rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| RankedEntry::from_row(row, first_rank + i as u32))
+        .collect();
+
+    let next = if entries.len() == limit as usize {
+        entries.last().map(|entry| entry.position().to_cursor())
+    } else {
+        None
+    };
+
+    make_response!(Ok, to_string(&LeaderboardPage { entries, next }).unwrap())
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct PlayerRank {
+    entry: RankedEntry,
+    /// Ordered from the best entry
+    above: Vec<RankedEntry>,
+    /// Ordered from the best entry
+    below: Vec<RankedEntry>
+}
+
+
+/// Finds where a player is on the endless leaderboard of a difficulty, along with the players around them
+#[rocket::get("/leaderboard/endless/rank?<difficulty>&<username>&<window>&<period>&<friends>")]
+pub async fn get_leaderboard_rank(difficulty: Difficulty, username: &str, window: Option<LeaderboardWindow>, period: Option<u32>, friends: Option<bool>, viewer: Option<AuthenticatedUser>, schedule: &State<TournamentSchedule>, mut bola_data: Connection<BolaData>) -> Response {
+    let board = match resolve_board(difficulty, window, period, friends, viewer, schedule, &mut bola_data).await {
+        Ok(x) => x,
+        Err(response) => return response
+    };
+
+    let position_sql = format!("SELECT Username, Levels, Time FROM {} AND Username = ?", board.source());
+    let position = match retry_busy!(
+        board.bind(sqlx::query(&position_sql))
+            .bind(username)
+            .fetch_optional(&mut *bola_data)
+    ) {
+        Ok(Some(row)) => RankedEntry::from_row(&row, 0).position(),
+        Ok(None) => return make_response!(NotFound, "Player has no entry on this leaderboard".into()),
+        Err(e) => return e.into_response("reading player's leaderboard entry")
+    };
+
+    let rank = match rank_of(&position, &board, &mut bola_data).await {
+        Ok(x) => x,
+        Err(response) => return response
+    };
+
+    // Walk upwards from the entry just above this one
+    let above_sql = format!("SELECT Username, Levels, Time FROM {} AND {AT_OR_ABOVE} AND Username != ? {REVERSE_RANK_ORDER} LIMIT ?", board.source());
+    let above = match retry_busy!(
+        bind_position(board.bind(sqlx::query(&above_sql)), &position)
+            .bind(&position.username)
+            .bind(NEIGHBOR_COUNT)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(rows) => rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| RankedEntry::from_row(row, rank - 1 - i as u32))
+            .rev()
+            .collect(),
+        Err(e) => return e.into_response("reading entries above rank")
+    };
+
+    let below_sql = format!("SELECT Username, Levels, Time FROM {} AND {BELOW} {RANK_ORDER} LIMIT ?", board.source());
+    let below = match retry_busy!(
+        bind_position(board.bind(sqlx::query(&below_sql)), &position)
+            .bind(NEIGHBOR_COUNT)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(rows) => rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| RankedEntry::from_row(row, rank + 1 + i as u32))
+            .collect(),
+        Err(e) => return e.into_response("reading entries below rank")
+    };
+
+    let entry = RankedEntry {
+        rank,
+        username: position.username,
+        levels: position.levels,
+        time: position.time
+    };
+
+    make_response!(Ok, to_string(&PlayerRank { entry, above, below }).unwrap())
+}
@@ -0,0 +1,416 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use rocket::futures::SinkExt;
+use rocket::serde::json::{from_str, to_string};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::{Notify, mpsc};
+use rocket_db_pools::sqlx::{self, Row, SqliteConnection, SqlitePool};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{MAX_DIFFICULTY, leaderboard, profiles};
+use super::leaderboard::RankedEntry;
+use crate::apps::db::{DbError, retry_busy};
+use crate::log::*;
+use crate::ws::{SlowConsumerPolicy, WsConnection, WsContext, WsHub, WsMember, WsRoute};
+
+/// The deepest a subscription can follow a leaderboard without following all of it
+const MAX_TOP: u32 = 1000;
+/// Subprotocol of clients that only want rank changes of what they subscribe to
+const LIVE_PROTOCOL: &str = "bola.leaderboard.v2";
+
+
+/// An entry as sent to clients that never subscribed
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LeaderboardEntry {
+    username: String,
+    difficulty: u8,
+    levels: u16,
+    time: f64
+}
+
+
+/// Tells clients that never subscribed to drop an entry
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct EntryRemoved<'a> {
+    /// Always "removed", which tells this apart from new entries
+    event: &'static str,
+    username: &'a str,
+    difficulty: u8
+}
+
+
+/// Sent by clients
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", tag = "action", rename_all = "snake_case")]
+enum Request {
+    /// Follows the all time leaderboard of a difficulty, only down to rank `top` if given
+    Subscribe { difficulty: u8, top: Option<u32> },
+    Unsubscribe { difficulty: u8 }
+}
+
+
+/// Sent to clients that subscribed
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+enum Update<'a> {
+    /// The whole subscribed part of a leaderboard. Replaces what the client had
+    Snapshot { difficulty: u8, entries: &'a [RankedEntry] },
+    /// An entry moved up to `new_rank`, from `old_rank` if it was on the leaderboard.
+    /// Entries from `new_rank` down to where it was move down by one. The ranks are equal when the entry
+    /// improved without passing anyone, in which case nothing else moves
+    RankChange { difficulty: u8, username: &'a str, levels: u16, time: f64, old_rank: Option<u32>, new_rank: u32 },
+    /// An entry left the leaderboard. Entries below it move up by one
+    Removed { difficulty: u8, username: &'a str, old_rank: u32 },
+    Error { message: &'a str }
+}
+
+
+impl Update<'_> {
+    fn to_message(&self) -> Message {
+        Message::Text(to_string(self).unwrap())
+    }
+}
+
+
+/// Raw entries, for clients that never subscribed
+const RAW_TOPIC: &str = "bola/leaderboards";
+
+
+/// Topic of the all time leaderboard of a difficulty, followed down to rank `top` if given
+fn topic(difficulty: u8, top: Option<u32>) -> String {
+    match top {
+        None => format!("{RAW_TOPIC}/{difficulty}"),
+        Some(top) => format!("{RAW_TOPIC}/{difficulty}/top/{top}")
+    }
+}
+
+
+/// Topics that follow the leaderboard of a difficulty, with the rank each follows it down to
+fn windows(hub: &WsHub, difficulty: u8) -> Vec<(String, Option<u32>)> {
+    let all = topic(difficulty, None);
+
+    hub.topics()
+        .into_iter()
+        .filter_map(|name| {
+            let top = match name.strip_prefix(&all)? {
+                "" => None,
+                rest => Some(rest.strip_prefix("/top/")?.parse().ok()?)
+            };
+            Some((name, top))
+        })
+        .collect()
+}
+
+
+/// What one client follows. Only its own task touches this
+struct Client {
+    id: u64,
+    /// Topic followed for each difficulty
+    subscriptions: HashMap<u8, String>,
+    /// Set until the client first subscribes. Until then it gets raw entries, like before subscriptions existed
+    raw: bool
+}
+
+
+#[derive(Default)]
+struct TurnState {
+    /// The next turn to hand out
+    next: u64,
+    /// The earliest turn that is not done
+    current: u64,
+    /// Turns that are done, but come after one that is not
+    done: BTreeSet<u64>
+}
+
+
+#[derive(Default)]
+struct Turns {
+    state: std::sync::Mutex<TurnState>,
+    advanced: Notify
+}
+
+
+static TURNS: Lazy<Turns> = Lazy::new(Default::default);
+
+
+/// A place in the order that leaderboard changes are published in. Dropping it lets the next turn go
+///
+/// Deltas only make sense applied in the order they were committed, and handlers commit and publish from
+/// different tasks, so each takes a turn while it holds the write lock and publishes once the turns before it are done
+pub(super) struct Turn(u64);
+
+
+/// Must be called while holding the write lock of the transaction whose changes will be published
+pub(super) fn take_turn() -> Turn {
+    let mut state = TURNS.state.lock().unwrap();
+    state.next += 1;
+    Turn(state.next - 1)
+}
+
+
+impl Turn {
+    /// Waits until every earlier turn is done
+    pub(super) async fn wait(&self) {
+        loop {
+            // Created before checking, so that a turn finishing in between still wakes this
+            let advanced = TURNS.advanced.notified();
+
+            if TURNS.state.lock().unwrap().current == self.0 {
+                return
+            }
+            advanced.await;
+        }
+    }
+}
+
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let mut guard = TURNS.state.lock().unwrap();
+        let state = &mut *guard;
+
+        state.done.insert(self.0);
+        while state.done.remove(&state.current) {
+            state.current += 1;
+        }
+
+        drop(guard);
+        TURNS.advanced.notify_waiters();
+    }
+}
+
+
+/// Announces that an entry is now at `entry.rank`, having been at `old_rank` if it was visible before
+pub(super) fn publish_entry(hub: &WsHub, entry: &RankedEntry, difficulty: u8, old_rank: Option<u32>) {
+    invalidate_snapshot();
+
+    hub.publish(RAW_TOPIC, Message::Text(to_string(&LeaderboardEntry {
+        username: entry.username.clone(),
+        difficulty,
+        levels: entry.levels,
+        time: entry.time
+    }).unwrap()));
+
+    let delta = Arc::new(Update::RankChange {
+        difficulty,
+        username: &entry.username,
+        levels: entry.levels,
+        time: entry.time,
+        old_rank,
+        new_rank: entry.rank
+    }.to_message());
+
+    // Entries only move up, so ones that end below a window never passed through it
+    for (name, top) in windows(hub, difficulty) {
+        if top.map_or(true, |top| entry.rank <= top) {
+            hub.publish(&name, delta.clone());
+        }
+    }
+}
+
+
+/// Announces that an entry at `old_rank` left the leaderboard
+///
+/// Clients following only the top of the leaderboard get a new snapshot, as an entry moved into their window
+pub(super) async fn publish_removal(hub: &WsHub, username: &str, difficulty: u8, old_rank: u32, conn: &mut SqliteConnection) {
+    invalidate_snapshot();
+
+    hub.publish(RAW_TOPIC, Message::Text(to_string(&EntryRemoved {
+        event: "removed",
+        username,
+        difficulty
+    }).unwrap()));
+
+    for (name, top) in windows(hub, difficulty) {
+        match top {
+            None => hub.publish(&name, Update::Removed { difficulty, username, old_rank }.to_message()),
+            Some(top) if old_rank <= top => match leaderboard::all_time_top(difficulty, Some(top), conn).await {
+                Ok(entries) => hub.publish(&name, Update::Snapshot { difficulty, entries: &entries }.to_message()),
+                Err(e) => error!("{e} while reading top {top} of difficulty {difficulty} after a removal")
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+
+/// Subscribes a client, replying with a snapshot that no update can overtake
+async fn subscribe(client: &mut Client, hub: &WsHub, difficulty: u8, top: Option<u32>, pool: &SqlitePool) -> bool {
+    let top = top.map(|x| x.clamp(1, MAX_TOP));
+    let name = topic(difficulty, top);
+
+    if client.raw {
+        hub.unsubscribe(client.id, RAW_TOPIC);
+        client.raw = false;
+    }
+    if let Some(old) = client.subscriptions.insert(difficulty, name.clone()) {
+        hub.unsubscribe(client.id, &old);
+    }
+
+    hub.subscribe(client.id, &name, async {
+        let entries = match pool.acquire().await {
+            Ok(mut conn) => leaderboard::all_time_top(difficulty, top, &mut conn).await,
+            Err(e) => Err(e.into())
+        };
+
+        match entries {
+            Ok(entries) => Update::Snapshot { difficulty, entries: &entries }.to_message(),
+            Err(e) => {
+                error!("{e} while reading snapshot of difficulty {difficulty}");
+                Update::Error { message: "Could not read the leaderboard" }.to_message()
+            }
+        }
+    }).await
+}
+
+
+/// Reads requests from a client until it leaves the hub
+async fn serve(mut client: Client, mut incoming: mpsc::Receiver<String>, hub: &WsHub, pool: &SqlitePool) {
+    while let Some(text) = incoming.recv().await {
+        let sent = match from_str(&text) {
+            Ok(Request::Subscribe { difficulty, top }) if (1..=MAX_DIFFICULTY).contains(&difficulty) => {
+                subscribe(&mut client, hub, difficulty, top, pool).await
+            }
+            Ok(Request::Unsubscribe { difficulty }) => {
+                if let Some(name) = client.subscriptions.remove(&difficulty) {
+                    hub.unsubscribe(client.id, &name);
+                }
+                true
+            }
+            Ok(Request::Subscribe { .. }) => hub.send(client.id, Update::Error { message: "Unknown difficulty" }.to_message()),
+            Err(_) => hub.send(client.id, Update::Error { message: "Invalid request" }.to_message())
+        };
+
+        if !sent {
+            break
+        }
+    }
+}
+
+
+async fn serialize_leaderboard(conn: &mut SqliteConnection) -> Result<String, DbError> {
+    let sql = format!("SELECT * FROM EndlessLeaderboard WHERE Hidden = 0 AND {}", profiles::NOT_PRIVATE);
+    let rows = retry_busy!(
+        sqlx::query(&sql)
+            .fetch_all(&mut *conn)
+    )?;
+
+    let entries: Vec<_> = rows
+        .iter()
+        .map(|row| LeaderboardEntry {
+            username: row.get_unchecked("Username"),
+            difficulty: row.get_unchecked("Difficulty"),
+            levels: row.get_unchecked("Levels"),
+            time: row.get_unchecked("Time")
+        })
+        .collect();
+
+    Ok(to_string(&entries).unwrap())
+}
+
+
+/// The whole leaderboard as sent to new clients, kept until the leaderboard changes
+struct SnapshotCache {
+    /// Bumped whenever the leaderboard changes, so that a snapshot read during a write is not kept
+    generation: u64,
+    data: Option<Arc<String>>
+}
+
+
+static SNAPSHOT: std::sync::Mutex<SnapshotCache> = std::sync::Mutex::new(SnapshotCache { generation: 0, data: None });
+
+
+/// Must be called whenever an entry is added, removed, hidden or made private
+pub(super) fn invalidate_snapshot() {
+    let mut cache = SNAPSHOT.lock().unwrap();
+    cache.generation += 1;
+    cache.data = None;
+}
+
+
+async fn snapshot(pool: &SqlitePool) -> Result<Arc<String>, DbError> {
+    let generation = {
+        let cache = SNAPSHOT.lock().unwrap();
+
+        if let Some(data) = &cache.data {
+            return Ok(data.clone())
+        }
+        cache.generation
+    };
+
+    let mut conn = pool.acquire().await?;
+    let data = Arc::new(serialize_leaderboard(&mut conn).await?);
+    let mut cache = SNAPSHOT.lock().unwrap();
+
+    if cache.generation == generation {
+        cache.data = Some(data.clone());
+    }
+
+    Ok(data)
+}
+
+
+/// Sends the whole leaderboard, then keeps the client updated
+///
+/// Clients can send `{"action": "subscribe", "difficulty": 1, "top": 10}` to get rank changes of one
+/// difficulty instead of raw entries, and `{"action": "unsubscribe", "difficulty": 1}` to stop.
+/// Clients speaking `LIVE_PROTOCOL` skip the whole leaderboard and only get what they subscribe to
+pub(super) fn ws_route(ctx: Arc<WsContext>) -> WsRoute {
+    WsRoute::new("/leaderboards", move |connection| accept_leaderboard_ws(connection, ctx.clone()))
+        .protocols(&[LIVE_PROTOCOL])
+}
+
+
+/// Subscribes straight away to all of the leaderboard of the difficulty in the path
+pub(super) fn ws_difficulty_route(ctx: Arc<WsContext>) -> WsRoute {
+    WsRoute::new("/leaderboards/<difficulty>", move |connection| accept_leaderboard_ws(connection, ctx.clone()))
+}
+
+
+async fn accept_leaderboard_ws(connection: WsConnection, ctx: Arc<WsContext>) {
+    let WsConnection { mut stream, params, protocol, .. } = connection;
+
+    let difficulty = match params.get("difficulty").map(|x| x.parse::<u8>()) {
+        None => None,
+        Some(Ok(x)) if (1..=MAX_DIFFICULTY).contains(&x) => Some(x),
+        Some(_) => {
+            let _ = stream.send(Update::Error { message: "Unknown difficulty" }.to_message()).await;
+            return
+        }
+    };
+
+    // A client that misses a delta would drift from the leaderboard unnoticed, so it is dropped instead and can reconnect
+    let WsMember { id, incoming } = ctx.hub.add_ws_with_policy(stream, &[], SlowConsumerPolicy::Disconnect);
+    let mut client = Client {
+        id,
+        subscriptions: HashMap::new(),
+        raw: false
+    };
+
+    // Clients that subscribe from the start never get raw entries
+    let sent = if let Some(difficulty) = difficulty {
+        subscribe(&mut client, &ctx.hub, difficulty, None, &ctx.bola_data).await
+    } else if protocol.is_none() {
+        client.raw = true;
+
+        ctx.hub.subscribe(id, RAW_TOPIC, async {
+            match snapshot(&ctx.bola_data).await {
+                Ok(data) => Message::Text(data.as_ref().clone()),
+                Err(e) => {
+                    error!("{e} while reading the leaderboard snapshot");
+                    Message::Text("Internal Error".into())
+                }
+            }
+        }).await
+    } else {
+        true
+    };
+
+    if sent {
+        serve(client, incoming, &ctx.hub, &ctx.bola_data).await;
+    }
+}
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS EndlessLeaderboard (
+    Username TEXT NOT NULL,
+    Difficulty INTEGER NOT NULL,
+    Levels INTEGER NOT NULL,
+    Time REAL NOT NULL,
+    UNIQUE (Username, Difficulty)
+);
+
+CREATE TABLE IF NOT EXISTS TournamentWinners (
+    Username TEXT NOT NULL,
+    Tournament INTEGER NOT NULL,
+    UNIQUE (Username, Tournament)
+);
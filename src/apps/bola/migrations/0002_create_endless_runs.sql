@@ -0,0 +1,16 @@
+CREATE TABLE EndlessRuns (
+    RunID INTEGER PRIMARY KEY AUTOINCREMENT,
+    Username TEXT NOT NULL,
+    Difficulty INTEGER NOT NULL,
+    Seed INTEGER NOT NULL,
+    StartedAt REAL NOT NULL,
+    CheckpointLevel INTEGER NOT NULL DEFAULT 0,
+    CheckpointAt REAL NOT NULL,
+    CheckpointCount INTEGER NOT NULL DEFAULT 0,
+    FinishedAt REAL,
+    Levels INTEGER
+);
+
+CREATE INDEX EndlessRunsByUser ON EndlessRuns (Username, StartedAt);
+
+ALTER TABLE EndlessLeaderboard ADD COLUMN RunID INTEGER REFERENCES EndlessRuns (RunID);
@@ -0,0 +1 @@
+CREATE INDEX EndlessLeaderboardRanking ON EndlessLeaderboard (Difficulty, Levels DESC, Time, Username);
@@ -0,0 +1,28 @@
+CREATE TABLE WindowedLeaderboard (
+    TimeWindow TEXT NOT NULL,
+    Period INTEGER NOT NULL,
+    Username TEXT NOT NULL,
+    Difficulty INTEGER NOT NULL,
+    Levels INTEGER NOT NULL,
+    Time REAL NOT NULL,
+    RunID INTEGER REFERENCES EndlessRuns (RunID),
+    UNIQUE (TimeWindow, Period, Username, Difficulty)
+);
+
+CREATE INDEX WindowedLeaderboardRanking ON WindowedLeaderboard (TimeWindow, Period, Difficulty, Levels DESC, Time, Username);
+
+-- SQLite takes the bare columns from the row holding MAX(Levels)
+-- Daily periods are days since the Unix epoch. 86400 must match SECS_PER_DAY in apps/bola/leaderboard.rs
+INSERT INTO WindowedLeaderboard (TimeWindow, Period, Username, Difficulty, Levels, Time, RunID)
+SELECT 'daily', CAST(FinishedAt / 86400 AS INTEGER), Username, Difficulty, MAX(Levels), ROUND(FinishedAt), RunID
+FROM EndlessRuns
+WHERE FinishedAt IS NOT NULL
+GROUP BY CAST(FinishedAt / 86400 AS INTEGER), Username, Difficulty;
+
+-- Weekly periods are tournament weeks. 604800 and 2761 are DIVISOR and WEEK_OFFSET from apps/bola/mod.rs, which
+-- numbered the weeks until tournaments moved to TournamentEvents. Migration 0006 turns the same weeks into events
+INSERT INTO WindowedLeaderboard (TimeWindow, Period, Username, Difficulty, Levels, Time, RunID)
+SELECT 'weekly', CAST(FinishedAt / 604800 AS INTEGER) - 2761, Username, Difficulty, MAX(Levels), ROUND(FinishedAt), RunID
+FROM EndlessRuns
+WHERE FinishedAt IS NOT NULL
+GROUP BY CAST(FinishedAt / 604800 AS INTEGER), Username, Difficulty;
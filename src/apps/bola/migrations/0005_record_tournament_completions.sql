@@ -0,0 +1,4 @@
+ALTER TABLE TournamentWinners ADD COLUMN CompletedAt REAL;
+ALTER TABLE TournamentWinners ADD COLUMN Score INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX TournamentWinnersByTournament ON TournamentWinners (Tournament, CompletedAt);
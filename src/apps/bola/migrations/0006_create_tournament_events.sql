@@ -0,0 +1,30 @@
+CREATE TABLE TournamentEvents (
+    EventID INTEGER PRIMARY KEY,
+    Since INTEGER NOT NULL,
+    Until INTEGER NOT NULL,
+    -- Seeded from the event id the way weeks were, instead of from the server secret
+    LegacySeed INTEGER NOT NULL DEFAULT 0,
+    Cancelled INTEGER NOT NULL DEFAULT 0,
+    CreatedBy TEXT
+);
+
+CREATE INDEX TournamentEventsBySince ON TournamentEvents (Since);
+
+CREATE TABLE TournamentPauses (
+    EventID INTEGER NOT NULL REFERENCES TournamentEvents (EventID),
+    Since INTEGER NOT NULL,
+    Until INTEGER NOT NULL
+);
+
+CREATE INDEX TournamentPausesByEvent ON TournamentPauses (EventID);
+
+-- Keep the weeks of the old hard coded schedule, up to and including the current one
+-- 604800 and 2761 are DIVISOR and WEEK_OFFSET from apps/bola/mod.rs before this migration, and must match migration 0004.
+-- Later schedules come from TournamentEvents, so these are the only definition of the old weeks left
+WITH RECURSIVE Weeks (Week) AS (
+    SELECT 0
+    UNION ALL
+    SELECT Week + 1 FROM Weeks WHERE Week < CAST(strftime('%s', 'now') AS INTEGER) / 604800 - 2761
+)
+INSERT INTO TournamentEvents (EventID, Since, Until, LegacySeed)
+SELECT Week, (Week + 2761) * 604800, (Week + 2762) * 604800, 1 FROM Weeks;
@@ -0,0 +1,5 @@
+CREATE TABLE PlayerSettings (
+    Username TEXT PRIMARY KEY NOT NULL,
+    -- Hides the player from leaderboards, tournament results and other players' views of their profile
+    Private INTEGER NOT NULL DEFAULT 0
+);
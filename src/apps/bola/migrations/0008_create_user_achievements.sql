@@ -0,0 +1,7 @@
+CREATE TABLE UserAchievements (
+    Username TEXT NOT NULL,
+    -- An id from achievements.json
+    AchievementID TEXT NOT NULL,
+    UnlockedAt REAL NOT NULL,
+    PRIMARY KEY (Username, AchievementID)
+);
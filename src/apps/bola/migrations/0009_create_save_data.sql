@@ -0,0 +1,9 @@
+CREATE TABLE SaveData (
+    Username TEXT NOT NULL,
+    Key TEXT NOT NULL,
+    -- Starts at 1 and goes up on every write, clients send it back in If-Match
+    Version INTEGER NOT NULL,
+    Data BLOB NOT NULL,
+    UpdatedAt REAL NOT NULL,
+    PRIMARY KEY (Username, Key)
+);
@@ -0,0 +1,10 @@
+CREATE TABLE Friendships (
+    Requester TEXT NOT NULL,
+    Addressee TEXT NOT NULL,
+    -- 'pending' until the addressee accepts, then 'accepted'
+    Status TEXT NOT NULL,
+    CreatedAt REAL NOT NULL,
+    PRIMARY KEY (Requester, Addressee)
+);
+
+CREATE INDEX FriendshipsByAddressee ON Friendships (Addressee, Status);
@@ -0,0 +1,21 @@
+ALTER TABLE EndlessLeaderboard ADD COLUMN Hidden INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE WindowedLeaderboard ADD COLUMN Hidden INTEGER NOT NULL DEFAULT 0;
+
+-- Banned players cannot submit runs or tournament wins
+CREATE TABLE LeaderboardBans (
+    Username TEXT PRIMARY KEY NOT NULL,
+    BannedBy TEXT NOT NULL,
+    Reason TEXT NOT NULL,
+    BannedAt REAL NOT NULL
+);
+
+CREATE TABLE ModerationLog (
+    LogID INTEGER PRIMARY KEY AUTOINCREMENT,
+    Moderator TEXT NOT NULL,
+    -- hide, unhide, remove, ban or unban
+    Action TEXT NOT NULL,
+    Username TEXT NOT NULL,
+    Difficulty INTEGER,
+    Reason TEXT NOT NULL,
+    CreatedAt REAL NOT NULL
+);
@@ -1,32 +1,124 @@
-use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{UNIX_EPOCH};
-use once_cell::sync::{Lazy};
-use rand::{SeedableRng, rngs::StdRng, RngCore};
 use rocket::form::prelude::ErrorKind;
 use rocket::serde::json::to_string;
-use rocket::{async_trait, FromForm};
+use rocket::{async_trait, FromForm, State};
 use rocket::form::{FromFormField, Errors, Error, Form, ValueField};
-use rocket::futures::{StreamExt, SinkExt};
 use rocket::http::Status;
 use rocket::serde::Serialize;
-use rocket_db_pools::sqlx::error::DatabaseError;
-use rocket_db_pools::sqlx::sqlite::SqliteError;
-use tokio_tungstenite::tungstenite::Message;
-use crate::ws::{WebSocket, WsList};
 
 use super::auth::AuthenticatedUser;
 use rocket_db_pools::{Database, Connection};
-use rocket_db_pools::sqlx::{self, Row, ConnectOptions};
-
-use super::{unwrap_result_or_log, Response, make_response, unwrap_option_or_log};
-use crate::{log::*, BOLA_DB_URL};
+use rocket_db_pools::sqlx::{self, Row};
+
+use super::{Response, make_response};
+use super::db::{DbError, begin_immediate, retry_busy};
+use super::migrations::Migration;
+use crate::log::*;
+use super::signing::ServerSecret;
+use crate::ws::{WsContext, WsHub, WsRouter};
+
+mod achievements;
+mod friends;
+mod leaderboard;
+mod live;
+mod moderation;
+mod profiles;
+mod runs;
+mod save;
+mod tournament;
+
+pub use achievements::{get_achievements, get_player_achievements};
+pub use friends::{
+    list_friends, send_friend_request, accept_friend_request, decline_friend_request, remove_friend
+};
+pub use leaderboard::{get_leaderboard_page, get_leaderboard_rank};
+pub use moderation::{
+    hide_entry, unhide_entry, remove_leaderboard_entry, ban_player, unban_player, get_moderation_log,
+    console_command as moderation_console_command
+};
+pub use profiles::{get_player_profile, set_privacy};
+pub use runs::{start_endless_run, add_endless_checkpoint};
+pub use save::{list_saves, get_save, put_save, delete_save};
+pub use tournament::{
+    get_tournament, win_tournament, get_tournament_results, list_tournaments,
+    create_tournament, cancel_tournament, pause_tournament, TournamentSchedule
+};
 
 #[derive(Database)]
 #[database("bola_data")]
 pub struct BolaData(sqlx::SqlitePool);
 
-const DIVISOR: u32 = 3600 * 24 * 7;
-const WEEK_OFFSET: u32 = 2761;
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create leaderboards",
+        sql: include_str!("migrations/0001_create_leaderboards.sql")
+    },
+    Migration {
+        version: 2,
+        name: "create endless runs",
+        sql: include_str!("migrations/0002_create_endless_runs.sql")
+    },
+    Migration {
+        version: 3,
+        name: "index leaderboard ranking",
+        sql: include_str!("migrations/0003_index_leaderboard_ranking.sql")
+    },
+    Migration {
+        version: 4,
+        name: "create windowed leaderboard",
+        sql: include_str!("migrations/0004_create_windowed_leaderboard.sql")
+    },
+    Migration {
+        version: 5,
+        name: "record tournament completions",
+        sql: include_str!("migrations/0005_record_tournament_completions.sql")
+    },
+    Migration {
+        version: 6,
+        name: "create tournament events",
+        sql: include_str!("migrations/0006_create_tournament_events.sql")
+    },
+    Migration {
+        version: 7,
+        name: "create player settings",
+        sql: include_str!("migrations/0007_create_player_settings.sql")
+    },
+    Migration {
+        version: 8,
+        name: "create user achievements",
+        sql: include_str!("migrations/0008_create_user_achievements.sql")
+    },
+    Migration {
+        version: 9,
+        name: "create save data",
+        sql: include_str!("migrations/0009_create_save_data.sql")
+    },
+    Migration {
+        version: 10,
+        name: "create friendships",
+        sql: include_str!("migrations/0010_create_friendships.sql")
+    },
+    Migration {
+        version: 11,
+        name: "create moderation",
+        sql: include_str!("migrations/0011_create_moderation.sql")
+    }
+];
+
+/// WebSocket endpoints of bola, to be mounted under /ws/bola
+pub fn ws_routes(ctx: &Arc<WsContext>) -> WsRouter {
+    let origins = ctx.config.ws_allowed_origins.as_slice();
+
+    WsRouter::new(ctx.limiter.clone())
+        .route(live::ws_route(ctx.clone()).origins(origins))
+        .route(live::ws_difficulty_route(ctx.clone()).origins(origins))
+        .route(achievements::ws_route(ctx.clone()).origins(origins))
+        .route(friends::ws_route(ctx.clone()).origins(origins))
+}
+
 
 const MAX_DIFFICULTY: u8 = 3;
 /// Starts from 1 and ends at 3 inclusive
@@ -52,319 +144,182 @@ impl<'r> FromFormField<'r> for Difficulty {
 }
 
 
-fn get_tournament_week() -> u32 {
-    (UNIX_EPOCH.elapsed().unwrap().as_secs() / DIVISOR as u64) as u32 - WEEK_OFFSET
-}
-
-
-#[rocket::get("/tournament")]
-pub fn get_tournament() -> String {
-    let week = get_tournament_week();
-
-    format!("{{\"week\": {}, \"seed\": {}, \"since\": {}, \"until\": {}}}",
-        week,
-        StdRng::seed_from_u64(week as u64).next_u32(),
-        (week + WEEK_OFFSET) * DIVISOR,
-        (week + WEEK_OFFSET + 1) * DIVISOR
-    )
-}
-
-
-pub struct WinTournamentForm {
-    week: u32
-}
-
-
-impl<'r> FromFormField<'r> for WinTournamentForm {
-    fn from_value(field:ValueField<'r>) -> rocket::form::Result<'r,Self> {
-        let raw_value = field.value.parse()?;
-
-        let current_week = get_tournament_week();
-        if raw_value != current_week {
-            return Err(Errors::from(vec![Error {
-                name: Some(field.name.into()),
-                value: Some(field.value.into()),
-                kind: ErrorKind::OutOfRange{ start: Some(current_week as isize), end: Some(current_week as isize) },
-                entity: rocket::form::prelude::Entity::Field
-            }]))
-        }
-
-        Ok(Self{
-            week: raw_value
-        })
-    }
-}
-
-
-#[rocket::post("/tournament", data = "<data>")]
-pub async fn win_tournament(data: Form<WinTournamentForm>, user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
-    let week = data.week;
-
-    match sqlx::query("INSERT INTO TournamentWinners (Username, Tournament) VALUES (?, ?)")
-        .bind(user.username)
-        .bind(week)
-        .execute(&mut *bola_data)
-        .await
-    {
-        Ok(_) => make_response!(Ok, "Win was recorded".into()),
-        Err(e) => {
-            let e = unwrap_option_or_log!(
-                e.as_database_error();
-                ("inserting into TournamentWinners")
-                return make_response!(BUG)
-            );
-
-            let e: &SqliteError = e.downcast_ref();
-
-            let string;
-            let code = match e.code().unwrap() {
-                std::borrow::Cow::Borrowed(x) => x,
-                std::borrow::Cow::Owned(x) => {
-                    string = x;
-                    string.as_str()
-                }
-            };
-
-            match code {
-                "2067" => make_response!(BadRequest, "Win is already recorded".into()),
-                _ => {
-                    default_error!(
-                        e,
-                        "inserting into TournamentWinners"
-                    );
-                    make_response!(BUG)
-                }
-            }
-        }
-    }
-}
-
-
-#[derive(Serialize, Default)]
+#[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
 struct AccountData {
     easy_max_level: u16,
     normal_max_level: u16,
     hard_max_level: u16,
     tournament_wins: u16,
-    won_tournament: bool
+    won_tournament: bool,
+    private: bool
 }
 
 
 #[rocket::get("/account")]
-pub async fn get_account(user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
-    let mut data = AccountData::default();
-
-    {
-        let mut fetch = sqlx::query("SELECT Difficulty, Levels FROM EndlessLeaderboard WHERE Username = ?")
-            .bind(user.username.clone())
-            .fetch(&mut *bola_data);
-
-        while let Some(result) = fetch.next().await {
-            let row = unwrap_result_or_log!(
-                result;
-                ("reading row in EndlessLeaderboard")
-                continue
-            );
-
-            let level: u16 = row.get_unchecked("Levels");
-
-            match row.get_unchecked::<u8, _>("Difficulty") {
-                1 => data.easy_max_level = level,
-                2 => data.normal_max_level  = level,
-                3 => data.hard_max_level = level,
-                x => {
-                    error!("Unknown difficulty: {x} found while getting account data for {}", user.username);
-                }
-            }
-        }
-    }
-
-    let row = unwrap_result_or_log!(
-        sqlx::query("SELECT COUNT(*) FROM TournamentWinners WHERE Username = ?")
-            .bind(user.username.clone())
+pub async fn get_account(user: AuthenticatedUser, schedule: &State<TournamentSchedule>, mut bola_data: Connection<BolaData>) -> Response {
+    let week = match schedule.current_event(&mut bola_data).await {
+        Ok(event) => event.map(|event| event.id),
+        Err(e) => return e.into_response("finding the current tournament")
+    };
+
+    // No week makes the Tournament comparison NULL, so nothing was won
+    let row = match retry_busy!(
+        sqlx::query(
+            "SELECT
+                (SELECT Levels FROM EndlessLeaderboard WHERE Username = ?1 AND Difficulty = 1) AS Easy,
+                (SELECT Levels FROM EndlessLeaderboard WHERE Username = ?1 AND Difficulty = 2) AS Normal,
+                (SELECT Levels FROM EndlessLeaderboard WHERE Username = ?1 AND Difficulty = 3) AS Hard,
+                (SELECT COUNT(*) FROM TournamentWinners WHERE Username = ?1) AS Wins,
+                EXISTS (SELECT 1 FROM TournamentWinners WHERE Username = ?1 AND Tournament = ?2) AS WonTournament,
+                (SELECT Private FROM PlayerSettings WHERE Username = ?1) AS Private"
+        )
+            .bind(&user.username)
+            .bind(week)
             .fetch_one(&mut *bola_data)
-            .await;
-            ("counting tournament wins for {}", user.username)
-            return make_response!(BUG)
-    );
-
-    data.tournament_wins = row.get_unchecked("COUNT(*)");
-
-    let row = unwrap_result_or_log!(
-        sqlx::query("SELECT COUNT(*) FROM TournamentWinners WHERE Username = ? AND Tournament = ?")
-            .bind(user.username.clone())
-            .bind(get_tournament_week())
-            .fetch_one(&mut *bola_data)
-            .await;
-            ("counting tournament wins for {}", user.username)
-            return make_response!(BUG)
-    );
-
-    let win_count: u32 = row.get_unchecked("COUNT(*)");
-
-    if win_count > 1 {
-        error!("User: {} has more than one win for this week: {}", user.username, get_tournament_week());
-    }
-
-    data.won_tournament = win_count > 0;
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading account data")
+    };
+
+    let data = AccountData {
+        easy_max_level: row.get_unchecked::<Option<u16>, _>("Easy").unwrap_or(0),
+        normal_max_level: row.get_unchecked::<Option<u16>, _>("Normal").unwrap_or(0),
+        hard_max_level: row.get_unchecked::<Option<u16>, _>("Hard").unwrap_or(0),
+        tournament_wins: row.get_unchecked("Wins"),
+        won_tournament: row.get_unchecked("WonTournament"),
+        private: row.get_unchecked::<Option<bool>, _>("Private").unwrap_or(false)
+    };
 
     make_response!(Ok, to_string(&data).unwrap())
 }
 
 
 #[derive(FromForm)]
-pub struct LeaderboardEntryRequest {
-    difficulty: Difficulty,
+pub struct LeaderboardEntryRequest<'a> {
+    /// Issued by `start_endless_run`
+    run_token: &'a str,
     levels: u16
 }
 
 
+/// Finishes an endless run, recording it on the leaderboard if it is the user's best
 #[rocket::post("/leaderboard/endless", data = "<data>")]
-pub async fn add_leaderboard_entry(data: Form<LeaderboardEntryRequest>, user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
-    let data = data;
-    let difficulty = data.difficulty.0;
+pub async fn add_leaderboard_entry(data: Form<LeaderboardEntryRequest<'_>>, user: AuthenticatedUser, secret: &State<ServerSecret>, schedule: &State<TournamentSchedule>, hub: &State<Arc<WsHub>>, mut bola_data: Connection<BolaData>) -> Response {
+    match moderation::is_banned(&user.username, &mut bola_data).await {
+        Ok(false) => {}
+        Ok(true) => return make_response!(Forbidden, "You are banned from leaderboards".into()),
+        Err(e) => return e.into_response("reading LeaderboardBans")
+    }
+
+    let run = match runs::load_run(data.run_token, &user.username, secret, &mut bola_data).await {
+        Ok(x) => x,
+        Err(response) => return response
+    };
+    let difficulty = run.difficulty;
     let levels = data.levels;
-    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs_f64().round();
+    let now = UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+    let current_time = now.round();
+
+    if let Err(reason) = run.check_progress(levels, now) {
+        return make_response!(BadRequest, reason.into())
+    }
+
+    let periods = match leaderboard::current_periods(schedule, &mut bola_data).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("finding the current period")
+    };
+
+    // A run is either finished and recorded everywhere, or left unfinished so that it can be submitted again.
+    // Ranks are read in the same transaction, so that they describe exactly this write
+    let mut tx = match begin_immediate(&mut bola_data).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("starting to record leaderboard entry")
+    };
+    let turn = live::take_turn();
+
+    let old_entry = match leaderboard::all_time_entry(&user.username, difficulty, &mut tx).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("ranking previous leaderboard entry")
+    };
+
+    match runs::finish_run(&run, levels, now, &mut tx).await {
+        Ok(true) => {}
+        Ok(false) => return make_response!(BadRequest, "Run is already finished".into()),
+        Err(e) => return e.into_response("finishing run in EndlessRuns")
+    }
+
+    if let Err(e) = leaderboard::record_windowed_entry(&user.username, difficulty, levels, current_time, run.id, &periods, &mut tx).await {
+        return e.into_response("upserting into WindowedLeaderboard")
+    }
 
-    match sqlx::query("INSERT INTO EndlessLeaderboard (Username, Difficulty, Levels, Time) VALUES (?, ?, ?, ?)")
-        .bind(user.username.clone())
+    let inserted = sqlx::query("INSERT INTO EndlessLeaderboard (Username, Difficulty, Levels, Time, RunID) VALUES (?, ?, ?, ?, ?)")
+        .bind(&user.username)
         .bind(difficulty)
         .bind(levels)
         .bind(current_time)
-        .execute(&mut *bola_data)
+        .bind(run.id)
+        .execute(&mut tx)
         .await
-    {
+        .map_err(DbError::from);
+
+    match inserted {
         Ok(_) => {}
-        Err(e) => match e.as_database_error() {
-            Some(e) => {
-                let e: &SqliteError = e.downcast_ref();
-
-                let string;
-                let code = match e.code().unwrap() {
-                    std::borrow::Cow::Borrowed(x) => x,
-                    std::borrow::Cow::Owned(x) => {
-                        string = x;
-                        string.as_str()
-                    }
-                };
-
-                match code {
-                    "2067" => match sqlx::query("UPDATE EndlessLeaderboard SET Levels = ?, Time = ? WHERE Username = ? AND Difficulty = ? AND Levels < ?")
-                        .bind(data.levels)
-                        .bind(current_time)
-                        .bind(user.username.clone())
-                        .bind(difficulty)
-                        .bind(levels)
-                        .execute(&mut *bola_data)
-                        .await
-                        {
-                            Ok(r) => if r.rows_affected() == 0 {
-                                    return make_response!(Ok, "Leaderboard entry was already recorded".into())
-                                } else if r.rows_affected() > 1 {
-                                    error!("Multiple rows affected bug when adding leaderboard entry");
-                                    return make_response!(BUG)
-                                }
-                            Err(e) => {
-                                default_error!(
-                                    e,
-                                    "inserting into EndlessLeaderboard"
-                                );
-                                return make_response!(BUG)
-                            }
-                        }
-                    _ => {
-                        default_error!(
-                            e,
-                            "inserting into EndlessLeaderboard"
-                        );
-                        return make_response!(BUG)
+        Err(DbError::UniqueViolation { .. }) => {
+            let updated = sqlx::query("UPDATE EndlessLeaderboard SET Levels = ?, Time = ?, RunID = ? WHERE Username = ? AND Difficulty = ? AND Levels < ?")
+                .bind(levels)
+                .bind(current_time)
+                .bind(run.id)
+                .bind(&user.username)
+                .bind(difficulty)
+                .bind(levels)
+                .execute(&mut tx)
+                .await;
+
+            match updated {
+                Ok(r) => if r.rows_affected() == 0 {
+                    if let Err(e) = tx.commit().await {
+                        return DbError::from(e).into_response("committing finished run")
                     }
+                    drop(turn);
+
+                    // The run still counts towards streaks
+                    achievements::evaluate(&user.username, hub, &mut bola_data).await;
+                    return make_response!(Ok, "Leaderboard entry was already recorded".into())
+                } else if r.rows_affected() > 1 {
+                    error!("Multiple rows affected bug when adding leaderboard entry");
+                    return make_response!(BUG)
                 }
-            }
-            None => {
-                default_error!(
-                    e,
-                    "inserting into EndlessLeaderboard"
-                );
-                return make_response!(BUG)
+                Err(e) => return DbError::from(e).into_response("updating EndlessLeaderboard")
             }
         }
+        Err(e) => return e.into_response("inserting into EndlessLeaderboard")
     }
 
-    STREAMS.send_all(Message::Text(to_string(
-        &LeaderboardEntry {
-            username: user.username,
-            difficulty,
-            levels,
-            time: current_time
-        }
-    ).unwrap())).await;
-
-    make_response!(Status::Ok, "Leaderboard entry was recorded".into())
-}
-
+    // Private players and hidden entries have no visible entry, and are left out of live updates
+    let new_entry = match leaderboard::all_time_entry(&user.username, difficulty, &mut tx).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("ranking leaderboard entry")
+    };
 
-async fn serialize_leaderboard() -> Option<String> {
-    let mut db = unwrap_result_or_log!(
-        sqlx::sqlite::SqliteConnectOptions::from_str(format!("sqlite://{}", BOLA_DB_URL.get().unwrap()).as_str())
-            .unwrap()
-            .connect()
-            .await;
-        ("connecting to bola_data")
-        return None
-    );
-
-    let mut fetch = sqlx::query("SELECT * FROM EndlessLeaderboard")
-        .fetch(&mut db);
-    
-    let mut entries = Vec::new();
-    
-    while let Some(result) = fetch.next().await {
-        let row = unwrap_result_or_log!(
-            result;
-            ("reading row in EndlessLeaderboard")
-            continue
-        );
-
-        entries.push(LeaderboardEntry {
-            username: row.get_unchecked("Username"),
-            difficulty: row.get_unchecked("Difficulty"),
-            levels: row.get_unchecked("Levels"),
-            time: row.get_unchecked("Time")
-        })
+    if let Err(e) = tx.commit().await {
+        return DbError::from(e).into_response("committing leaderboard entry")
     }
-    
-    Some(to_string(&entries).unwrap())
-}
-
 
-static STREAMS: Lazy<WsList> = Lazy::new(WsList::new);
+    if let Some(entry) = new_entry {
+        let unchanged = old_entry.as_ref().map_or(false, |old| {
+            old.rank == entry.rank && old.levels == entry.levels && old.time == entry.time
+        });
 
-
-#[derive(Serialize)]
-#[serde(crate = "rocket::serde")]
-struct LeaderboardEntry {
-    username: String,
-    difficulty: u8,
-    levels: u16,
-    time: f64
-}
-
-
-pub fn accept_leaderboard_ws(mut stream: WebSocket) {
-    rocket::tokio::spawn(async move {
-        let data = if let Some(x) = serialize_leaderboard().await {
-            x
-        } else {
-            let _ = stream.send(Message::Text("Internal Error".into())).await;
-            return
-        };
-
-        if stream.send(Message::Text(data)).await.is_err() {
-            return
+        if !unchanged {
+            turn.wait().await;
+            live::publish_entry(hub, &entry, difficulty, old_entry.map(|old| old.rank));
         }
+    }
+    drop(turn);
 
-        STREAMS.add_ws(stream).await;
-    });
+    achievements::evaluate(&user.username, hub, &mut bola_data).await;
+    friends::notify_friends(&user.username, friends::Notification::LeaderboardEntry { username: &user.username, difficulty, levels }, hub, &mut bola_data).await;
+
+    make_response!(Status::Ok, "Leaderboard entry was recorded".into())
 }
@@ -0,0 +1,355 @@
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use clap::ArgMatches;
+use rocket::{FromForm, State};
+use rocket::form::Form;
+use rocket::serde::json::to_string;
+use rocket::serde::Serialize;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Connection as _, Row, Sqlite, SqliteConnection, SqlitePool, Transaction};
+
+use super::{BolaData, Difficulty, MAX_DIFFICULTY, leaderboard, live};
+use crate::apps::auth::Moderator;
+use crate::apps::db::{DbError, begin_immediate, retry_busy};
+use crate::apps::{Response, make_response};
+use crate::log::*;
+use crate::ws::WsHub;
+
+const DEFAULT_LOG_COUNT: u32 = 50;
+const MAX_LOG_COUNT: u32 = 200;
+/// Recorded as the moderator of actions taken from the console
+const CONSOLE_MODERATOR: &str = "console";
+
+
+async fn log_action(tx: &mut Transaction<'_, Sqlite>, moderator: &str, action: &str, username: &str, difficulty: Option<u8>, reason: &str) -> Result<(), DbError> {
+    sqlx::query("INSERT INTO ModerationLog (Moderator, Action, Username, Difficulty, Reason, CreatedAt) VALUES (?, ?, ?, ?, ?, ?)")
+        .bind(moderator)
+        .bind(action)
+        .bind(username)
+        .bind(difficulty)
+        .bind(reason)
+        .bind(UNIX_EPOCH.elapsed().unwrap().as_secs_f64())
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(())
+}
+
+
+pub(super) async fn is_banned(username: &str, bola_data: &mut Connection<BolaData>) -> Result<bool, DbError> {
+    retry_busy!(
+        sqlx::query("SELECT 1 FROM LeaderboardBans WHERE Username = ?")
+            .bind(username)
+            .fetch_optional(&mut **bola_data)
+    ).map(|row| row.is_some())
+}
+
+
+/// Hides or shows a player's entries on every leaderboard of a difficulty. Returns false if there was nothing to change
+pub async fn set_hidden(moderator: &str, username: &str, difficulty: u8, hidden: bool, reason: &str, hub: &WsHub, conn: &mut SqliteConnection) -> Result<bool, DbError> {
+    // Ranks are read in the transaction, so that no submission can move them before the write
+    let mut tx = begin_immediate(conn).await?;
+    let turn = live::take_turn();
+    let old = leaderboard::all_time_entry(username, difficulty, &mut tx).await?;
+
+    let result = sqlx::query("UPDATE EndlessLeaderboard SET Hidden = ? WHERE Username = ? AND Difficulty = ? AND Hidden != ?")
+        .bind(hidden)
+        .bind(username)
+        .bind(difficulty)
+        .bind(hidden)
+        .execute(&mut tx)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false)
+    }
+
+    sqlx::query("UPDATE WindowedLeaderboard SET Hidden = ? WHERE Username = ? AND Difficulty = ?")
+        .bind(hidden)
+        .bind(username)
+        .bind(difficulty)
+        .execute(&mut tx)
+        .await?;
+
+    log_action(&mut tx, moderator, if hidden { "hide" } else { "unhide" }, username, Some(difficulty), reason).await?;
+    let new = leaderboard::all_time_entry(username, difficulty, &mut tx).await?;
+    tx.commit().await?;
+
+    turn.wait().await;
+
+    if hidden {
+        if let Some(old) = old {
+            live::publish_removal(hub, username, difficulty, old.rank, conn).await;
+        }
+    } else if let Some(entry) = new {
+        live::publish_entry(hub, &entry, difficulty, None);
+    }
+
+    Ok(true)
+}
+
+
+/// Deletes a player's entries on every leaderboard of a difficulty, so that their next run starts over
+pub async fn remove_entry(moderator: &str, username: &str, difficulty: u8, reason: &str, hub: &WsHub, conn: &mut SqliteConnection) -> Result<bool, DbError> {
+    let mut tx = begin_immediate(conn).await?;
+    let turn = live::take_turn();
+    let old = leaderboard::all_time_entry(username, difficulty, &mut tx).await?;
+
+    let result = sqlx::query("DELETE FROM EndlessLeaderboard WHERE Username = ? AND Difficulty = ?")
+        .bind(username)
+        .bind(difficulty)
+        .execute(&mut tx)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false)
+    }
+
+    sqlx::query("DELETE FROM WindowedLeaderboard WHERE Username = ? AND Difficulty = ?")
+        .bind(username)
+        .bind(difficulty)
+        .execute(&mut tx)
+        .await?;
+
+    log_action(&mut tx, moderator, "remove", username, Some(difficulty), reason).await?;
+    tx.commit().await?;
+
+    turn.wait().await;
+
+    // Private players were never on the live leaderboard
+    if let Some(old) = old {
+        live::publish_removal(hub, username, difficulty, old.rank, conn).await;
+    }
+
+    Ok(true)
+}
+
+
+/// Returns false if the player was already banned
+pub async fn ban(moderator: &str, username: &str, reason: &str, conn: &mut SqliteConnection) -> Result<bool, DbError> {
+    let mut tx = conn.begin().await?;
+
+    match sqlx::query("INSERT INTO LeaderboardBans (Username, BannedBy, Reason, BannedAt) VALUES (?, ?, ?, ?)")
+        .bind(username)
+        .bind(moderator)
+        .bind(reason)
+        .bind(UNIX_EPOCH.elapsed().unwrap().as_secs_f64())
+        .execute(&mut tx)
+        .await
+        .map_err(DbError::from)
+    {
+        Ok(_) => {}
+        Err(DbError::UniqueViolation { .. }) => return Ok(false),
+        Err(e) => return Err(e)
+    }
+
+    log_action(&mut tx, moderator, "ban", username, None, reason).await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+
+/// Returns false if the player was not banned
+pub async fn unban(moderator: &str, username: &str, reason: &str, conn: &mut SqliteConnection) -> Result<bool, DbError> {
+    let mut tx = conn.begin().await?;
+
+    let result = sqlx::query("DELETE FROM LeaderboardBans WHERE Username = ?")
+        .bind(username)
+        .execute(&mut tx)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false)
+    }
+
+    log_action(&mut tx, moderator, "unban", username, None, reason).await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LogEntry {
+    id: i64,
+    moderator: String,
+    action: String,
+    username: String,
+    /// Missing for bans
+    difficulty: Option<u8>,
+    reason: String,
+    created_at: f64
+}
+
+
+/// Lists moderation actions from the most recent one, older than the action with id `before` if given
+async fn read_log(before: Option<i64>, limit: u32, conn: &mut SqliteConnection) -> Result<Vec<LogEntry>, DbError> {
+    let rows = sqlx::query("SELECT * FROM ModerationLog WHERE LogID < ? ORDER BY LogID DESC LIMIT ?")
+        .bind(before.unwrap_or(i64::MAX))
+        .bind(limit.clamp(1, MAX_LOG_COUNT))
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| LogEntry {
+            id: row.get_unchecked("LogID"),
+            moderator: row.get_unchecked("Moderator"),
+            action: row.get_unchecked("Action"),
+            username: row.get_unchecked("Username"),
+            difficulty: row.get_unchecked("Difficulty"),
+            reason: row.get_unchecked("Reason"),
+            created_at: row.get_unchecked("CreatedAt")
+        })
+        .collect())
+}
+
+
+#[derive(FromForm)]
+pub struct EntryAction<'a> {
+    username: &'a str,
+    difficulty: Difficulty,
+    reason: &'a str
+}
+
+
+#[derive(FromForm)]
+pub struct BanAction<'a> {
+    username: &'a str,
+    reason: &'a str
+}
+
+
+#[rocket::post("/moderation/entries/hide", data = "<data>")]
+pub async fn hide_entry(data: Form<EntryAction<'_>>, moderator: Moderator, hub: &State<Arc<WsHub>>, mut bola_data: Connection<BolaData>) -> Response {
+    match set_hidden(&moderator.username, data.username, data.difficulty.0, true, data.reason, hub, &mut bola_data).await {
+        Ok(true) => make_response!(Ok, "Entry was hidden".into()),
+        Ok(false) => make_response!(NotFound, "No visible entry to hide".into()),
+        Err(e) => e.into_response("hiding leaderboard entry")
+    }
+}
+
+
+#[rocket::post("/moderation/entries/unhide", data = "<data>")]
+pub async fn unhide_entry(data: Form<EntryAction<'_>>, moderator: Moderator, hub: &State<Arc<WsHub>>, mut bola_data: Connection<BolaData>) -> Response {
+    match set_hidden(&moderator.username, data.username, data.difficulty.0, false, data.reason, hub, &mut bola_data).await {
+        Ok(true) => make_response!(Ok, "Entry was shown".into()),
+        Ok(false) => make_response!(NotFound, "No hidden entry to show".into()),
+        Err(e) => e.into_response("showing leaderboard entry")
+    }
+}
+
+
+#[rocket::post("/moderation/entries/remove", data = "<data>")]
+pub async fn remove_leaderboard_entry(data: Form<EntryAction<'_>>, moderator: Moderator, hub: &State<Arc<WsHub>>, mut bola_data: Connection<BolaData>) -> Response {
+    match remove_entry(&moderator.username, data.username, data.difficulty.0, data.reason, hub, &mut bola_data).await {
+        Ok(true) => make_response!(Ok, "Entry was removed".into()),
+        Ok(false) => make_response!(NotFound, "No entry to remove".into()),
+        Err(e) => e.into_response("removing leaderboard entry")
+    }
+}
+
+
+#[rocket::post("/moderation/bans", data = "<data>")]
+pub async fn ban_player(data: Form<BanAction<'_>>, moderator: Moderator, mut bola_data: Connection<BolaData>) -> Response {
+    match ban(&moderator.username, data.username, data.reason, &mut bola_data).await {
+        Ok(true) => make_response!(Ok, "Player was banned".into()),
+        Ok(false) => make_response!(BadRequest, "Player is already banned".into()),
+        Err(e) => e.into_response("banning player")
+    }
+}
+
+
+#[rocket::post("/moderation/bans/lift", data = "<data>")]
+pub async fn unban_player(data: Form<BanAction<'_>>, moderator: Moderator, mut bola_data: Connection<BolaData>) -> Response {
+    match unban(&moderator.username, data.username, data.reason, &mut bola_data).await {
+        Ok(true) => make_response!(Ok, "Player was unbanned".into()),
+        Ok(false) => make_response!(NotFound, "Player is not banned".into()),
+        Err(e) => e.into_response("unbanning player")
+    }
+}
+
+
+#[rocket::get("/moderation/log?<before>&<limit>")]
+pub async fn get_moderation_log(before: Option<i64>, limit: Option<u32>, _moderator: Moderator, mut bola_data: Connection<BolaData>) -> Response {
+    match read_log(before, limit.unwrap_or(DEFAULT_LOG_COUNT), &mut bola_data).await {
+        Ok(entries) => make_response!(Ok, to_string(&entries).unwrap()),
+        Err(e) => e.into_response("reading ModerationLog")
+    }
+}
+
+
+/// Runs a `moderate` console command, returning what to write back to the console
+pub async fn console_command(matches: &ArgMatches, hub: &WsHub, pool: &SqlitePool) -> String {
+    let mut conn = match pool.acquire().await {
+        Ok(x) => x,
+        Err(e) => return format!("Could not connect to bola_data: {e}")
+    };
+
+    let (command, args) = matches.subcommand().unwrap();
+    // Not every command defines every argument
+    let arg = |id: &str| args.try_get_one::<String>(id).ok().flatten().map(String::as_str);
+    let username = arg("username").unwrap_or_default();
+    let reason = args
+        .try_get_many::<String>("reason")
+        .ok()
+        .flatten()
+        .map(|words| words.map(String::as_str).collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
+    let difficulty = match arg("difficulty").map(str::parse::<u8>) {
+        Some(Ok(x)) if (1..=MAX_DIFFICULTY).contains(&x) => x,
+        Some(_) => return format!("Difficulty must be from 1 to {MAX_DIFFICULTY}"),
+        None => 0
+    };
+
+    let result = match command {
+        "hide" => set_hidden(CONSOLE_MODERATOR, username, difficulty, true, &reason, hub, &mut conn).await
+            .map(|done| if done { "Entry was hidden" } else { "No visible entry to hide" }),
+        "unhide" => set_hidden(CONSOLE_MODERATOR, username, difficulty, false, &reason, hub, &mut conn).await
+            .map(|done| if done { "Entry was shown" } else { "No hidden entry to show" }),
+        "remove" => remove_entry(CONSOLE_MODERATOR, username, difficulty, &reason, hub, &mut conn).await
+            .map(|done| if done { "Entry was removed" } else { "No entry to remove" }),
+        "ban" => ban(CONSOLE_MODERATOR, username, &reason, &mut conn).await
+            .map(|done| if done { "Player was banned" } else { "Player is already banned" }),
+        "unban" => unban(CONSOLE_MODERATOR, username, &reason, &mut conn).await
+            .map(|done| if done { "Player was unbanned" } else { "Player is not banned" }),
+        "log" => {
+            let limit = arg("limit").and_then(|x| x.parse().ok()).unwrap_or(DEFAULT_LOG_COUNT);
+
+            return match read_log(None, limit, &mut conn).await {
+                Ok(entries) => entries
+                    .iter()
+                    .map(|entry| format!(
+                        "#{} {} {} {}{}: {}",
+                        entry.id,
+                        entry.moderator,
+                        entry.action,
+                        entry.username,
+                        entry.difficulty.map(|x| format!(" on difficulty {x}")).unwrap_or_default(),
+                        entry.reason
+                    ))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Err(e) => {
+                    error!("{e} while reading ModerationLog from the console");
+                    e.to_string()
+                }
+            }
+        }
+        cmd => {
+            error!("Received the following moderate command from client console: {cmd}");
+            return format!("Unknown command: {cmd}")
+        }
+    };
+
+    match result {
+        Ok(msg) => msg.to_string(),
+        Err(e) => {
+            error!("{e} while running moderate {command} from the console");
+            e.to_string()
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use rocket::{FromForm, State};
+use rocket::form::Form;
+use rocket::serde::json::to_string;
+use rocket::serde::Serialize;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Row, SqliteConnection};
+
+use super::{BolaData, MAX_DIFFICULTY, leaderboard, live};
+use super::leaderboard::{RankedEntry, SECS_PER_DAY};
+use crate::apps::auth::AuthenticatedUser;
+use crate::apps::db::{DbError, begin_immediate, retry_busy};
+use crate::apps::{Response, make_response};
+use crate::ws::WsHub;
+
+const RECENT_RUN_COUNT: u32 = 10;
+
+/// Excludes players who made themselves private. Must be used where a Username column is in scope
+pub(super) const NOT_PRIVATE: &str = "Username NOT IN (SELECT Username FROM PlayerSettings WHERE Private = 1)";
+
+
+/// Returns the player's current and longest streaks of days with a finished run
+pub(super) async fn streaks(username: &str, bola_data: &mut Connection<BolaData>) -> Result<(u32, u32), DbError> {
+    let rows = retry_busy!(
+        sqlx::query("SELECT DISTINCT CAST(FinishedAt / ? AS INTEGER) AS Day FROM EndlessRuns WHERE Username = ? AND FinishedAt IS NOT NULL ORDER BY Day")
+            .bind(SECS_PER_DAY as i64)
+            .bind(username)
+            .fetch_all(&mut **bola_data)
+    )?;
+    let days: Vec<i64> = rows.iter().map(|row| row.get_unchecked("Day")).collect();
+
+    Ok(count_streaks(&days, UNIX_EPOCH.elapsed().unwrap().as_secs() as i64 / SECS_PER_DAY as i64))
+}
+
+
+pub(super) async fn is_private(username: &str, bola_data: &mut Connection<BolaData>) -> Result<bool, DbError> {
+    retry_busy!(
+        sqlx::query("SELECT Private FROM PlayerSettings WHERE Username = ?")
+            .bind(username)
+            .fetch_optional(&mut **bola_data)
+    ).map(|row| row.map_or(false, |row| row.get_unchecked("Private")))
+}
+
+
+/// Returns the current and longest runs of consecutive days, given sorted distinct day numbers
+///
+/// The current streak is kept alive until the end of the day after the last one played
+fn count_streaks(days: &[i64], today: i64) -> (u32, u32) {
+    let mut longest = 0;
+    let mut running = 0;
+    let mut previous = None;
+
+    for &day in days {
+        running = if previous == Some(day - 1) { running + 1 } else { 1 };
+        longest = longest.max(running);
+        previous = Some(day);
+    }
+
+    match previous {
+        Some(day) if day >= today - 1 => (running, longest),
+        _ => (0, longest)
+    }
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct DifficultyStats {
+    difficulty: u8,
+    /// Finished runs
+    runs: u32,
+    /// Levels of the player's entry on the all time leaderboard
+    best_levels: u16,
+    total_levels: u32
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct RecentRun {
+    difficulty: u8,
+    levels: u16,
+    started_at: f64,
+    finished_at: f64
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Profile {
+    username: String,
+    /// Unix time of the player's first run, leaderboard entry or tournament win
+    first_seen: f64,
+    total_runs: u32,
+    /// Seconds spent in finished runs
+    play_time: f64,
+    tournament_wins: u32,
+    /// In days
+    current_streak: u32,
+    /// In days
+    longest_streak: u32,
+    private: bool,
+    difficulties: Vec<DifficultyStats>,
+    /// From the most recent one
+    recent_runs: Vec<RecentRun>
+}
+
+
+/// Shows a player's statistics. Private players can only see their own
+#[rocket::get("/players/<username>")]
+pub async fn get_player_profile(username: &str, viewer: Option<AuthenticatedUser>, mut bola_data: Connection<BolaData>) -> Response {
+    let not_found = || make_response!(NotFound, "Player does not exist".into());
+
+    let row = match retry_busy!(
+        sqlx::query(
+            "SELECT
+                (SELECT TOTAL(FinishedAt - StartedAt) FROM EndlessRuns WHERE Username = ?1 AND FinishedAt IS NOT NULL) AS PlayTime,
+                (SELECT MIN(StartedAt) FROM EndlessRuns WHERE Username = ?1) AS FirstRun,
+                (SELECT MIN(Time) FROM EndlessLeaderboard WHERE Username = ?1) AS FirstEntry,
+                (SELECT MIN(CompletedAt) FROM TournamentWinners WHERE Username = ?1) AS FirstWin,
+                (SELECT COUNT(*) FROM TournamentWinners WHERE Username = ?1) AS Wins,
+                (SELECT Private FROM PlayerSettings WHERE Username = ?1) AS Private"
+        )
+            .bind(username)
+            .fetch_one(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading player profile")
+    };
+
+    let private = row.get_unchecked::<Option<bool>, _>("Private").unwrap_or(false);
+    let tournament_wins: u32 = row.get_unchecked("Wins");
+    let first_seen = ["FirstRun", "FirstEntry", "FirstWin"]
+        .into_iter()
+        .filter_map(|column| row.get_unchecked::<Option<f64>, _>(column))
+        .reduce(f64::min);
+
+    if private && viewer.map_or(true, |viewer| viewer.username != username) {
+        return not_found()
+    }
+
+    // Wins recorded before completion times were have nothing else to date the player by
+    let first_seen = match first_seen {
+        Some(x) => x,
+        None if tournament_wins > 0 => 0.0,
+        None => return not_found()
+    };
+
+    let mut difficulties: Vec<_> = (1..=MAX_DIFFICULTY)
+        .map(|difficulty| DifficultyStats {
+            difficulty,
+            runs: 0,
+            best_levels: 0,
+            total_levels: 0
+        })
+        .collect();
+
+    let rows = match retry_busy!(
+        sqlx::query("SELECT Difficulty, COUNT(*) AS Runs, SUM(Levels) AS TotalLevels FROM EndlessRuns WHERE Username = ? AND FinishedAt IS NOT NULL GROUP BY Difficulty")
+            .bind(username)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("summarizing EndlessRuns")
+    };
+
+    for row in rows {
+        if let Some(stats) = difficulties.iter_mut().find(|stats| stats.difficulty == row.get_unchecked::<u8, _>("Difficulty")) {
+            stats.runs = row.get_unchecked("Runs");
+            stats.total_levels = row.get_unchecked("TotalLevels");
+        }
+    }
+
+    let rows = match retry_busy!(
+        sqlx::query("SELECT Difficulty, Levels FROM EndlessLeaderboard WHERE Username = ? AND Hidden = 0")
+            .bind(username)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading from EndlessLeaderboard")
+    };
+
+    for row in rows {
+        if let Some(stats) = difficulties.iter_mut().find(|stats| stats.difficulty == row.get_unchecked::<u8, _>("Difficulty")) {
+            stats.best_levels = row.get_unchecked("Levels");
+        }
+    }
+
+    let rows = match retry_busy!(
+        sqlx::query("SELECT Difficulty, Levels, StartedAt, FinishedAt FROM EndlessRuns WHERE Username = ? AND FinishedAt IS NOT NULL ORDER BY FinishedAt DESC LIMIT ?")
+            .bind(username)
+            .bind(RECENT_RUN_COUNT)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading recent runs")
+    };
+
+    let recent_runs = rows
+        .iter()
+        .map(|row| RecentRun {
+            difficulty: row.get_unchecked("Difficulty"),
+            levels: row.get_unchecked("Levels"),
+            started_at: row.get_unchecked("StartedAt"),
+            finished_at: row.get_unchecked("FinishedAt")
+        })
+        .collect();
+
+    let (current_streak, longest_streak) = match streaks(username, &mut bola_data).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading days played")
+    };
+
+    make_response!(Ok, to_string(&Profile {
+        username: username.to_string(),
+        first_seen,
+        total_runs: difficulties.iter().map(|stats| stats.runs).sum(),
+        play_time: row.get_unchecked("PlayTime"),
+        tournament_wins,
+        current_streak,
+        longest_streak,
+        private,
+        difficulties,
+        recent_runs
+    }).unwrap())
+}
+
+
+#[derive(FromForm)]
+pub struct PrivacyForm {
+    private: bool
+}
+
+
+/// The player's entry on the all time leaderboard of each difficulty, from the first difficulty
+async fn all_time_entries(username: &str, conn: &mut SqliteConnection) -> Result<Vec<Option<RankedEntry>>, DbError> {
+    let mut entries = Vec::new();
+
+    for difficulty in 1..=MAX_DIFFICULTY {
+        entries.push(leaderboard::all_time_entry(username, difficulty, conn).await?);
+    }
+    Ok(entries)
+}
+
+
+/// Hides or shows the user in leaderboards, tournament results and profiles viewed by others
+#[rocket::post("/account/privacy", data = "<data>")]
+pub async fn set_privacy(data: Form<PrivacyForm>, user: AuthenticatedUser, hub: &State<Arc<WsHub>>, mut bola_data: Connection<BolaData>) -> Response {
+    // Entries are read around the write, so that live clients are told exactly what appeared or left
+    let mut tx = match begin_immediate(&mut bola_data).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("starting to update privacy")
+    };
+    let turn = live::take_turn();
+
+    let before = match all_time_entries(&user.username, &mut tx).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("ranking entries before privacy change")
+    };
+
+    let upserted = sqlx::query(
+        "INSERT INTO PlayerSettings (Username, Private) VALUES (?, ?)
+        ON CONFLICT (Username) DO UPDATE SET Private = excluded.Private"
+    )
+        .bind(&user.username)
+        .bind(data.private)
+        .execute(&mut tx)
+        .await;
+
+    if let Err(e) = upserted {
+        return DbError::from(e).into_response("upserting into PlayerSettings")
+    }
+
+    let after = match all_time_entries(&user.username, &mut tx).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("ranking entries after privacy change")
+    };
+
+    if let Err(e) = tx.commit().await {
+        return DbError::from(e).into_response("committing privacy change")
+    }
+
+    turn.wait().await;
+
+    for (difficulty, (before, after)) in (1..=MAX_DIFFICULTY).zip(before.into_iter().zip(after)) {
+        match (before, after) {
+            (Some(old), None) => live::publish_removal(hub, &user.username, difficulty, old.rank, &mut bola_data).await,
+            (None, Some(entry)) => live::publish_entry(hub, &entry, difficulty, None),
+            _ => {}
+        }
+    }
+
+    make_response!(Ok, "Privacy was updated".into())
+}
@@ -0,0 +1,257 @@
+use std::time::UNIX_EPOCH;
+
+use rand::{thread_rng, RngCore};
+use rocket::form::Form;
+use rocket::serde::json::to_string;
+use rocket::serde::Serialize;
+use rocket::{FromForm, State};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Row, SqliteConnection};
+
+use super::{BolaData, Difficulty, MAX_DIFFICULTY};
+use crate::apps::auth::AuthenticatedUser;
+use crate::apps::db::{DbError, retry_busy};
+use crate::apps::signing::ServerSecret;
+use crate::apps::{Response, make_response};
+
+/// The fastest a single level can plausibly be cleared on each difficulty, in seconds
+const MIN_SECS_PER_LEVEL: [f64; MAX_DIFFICULTY as usize] = [3.0, 4.0, 5.0];
+/// Clients must send a checkpoint at least this often
+const MAX_LEVELS_PER_CHECKPOINT: u16 = 10;
+/// Runs that have not been submitted after this long can no longer be submitted
+const MAX_RUN_SECS: f64 = 6.0 * 3600.0;
+
+
+fn run_token_message(run_id: i64, username: &str) -> String {
+    format!("endless-run:{run_id}:{username}")
+}
+
+
+/// Makes a token that only `username` can use to refer to the given run
+fn make_run_token(secret: &ServerSecret, run_id: i64, username: &str) -> String {
+    format!("{run_id}.{}", secret.sign(&run_token_message(run_id, username)))
+}
+
+
+/// An endless run as stored in EndlessRuns
+pub(super) struct EndlessRun {
+    pub(super) id: i64,
+    pub(super) difficulty: u8,
+    started_at: f64,
+    checkpoint_level: u16,
+    checkpoint_at: f64,
+    finished: bool
+}
+
+
+impl EndlessRun {
+    /// Checks that reaching `level` at `now` is plausible given how the run has progressed so far
+    pub(super) fn check_progress(&self, level: u16, now: f64) -> Result<(), &'static str> {
+        if self.finished {
+            return Err("Run is already finished")
+        }
+        if now - self.started_at > MAX_RUN_SECS {
+            return Err("Run has expired")
+        }
+        if level < self.checkpoint_level {
+            return Err("Run cannot lose levels")
+        }
+
+        let new_levels = level - self.checkpoint_level;
+
+        if new_levels > MAX_LEVELS_PER_CHECKPOINT {
+            return Err("Too many levels since the last checkpoint")
+        }
+
+        let min_secs = MIN_SECS_PER_LEVEL[self.difficulty as usize - 1];
+
+        if now - self.checkpoint_at < new_levels as f64 * min_secs || now - self.started_at < level as f64 * min_secs {
+            return Err("Levels were cleared implausibly fast")
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Verifies that `token` was issued to `username` and fetches the run it refers to
+pub(super) async fn load_run(token: &str, username: &str, secret: &ServerSecret, bola_data: &mut Connection<BolaData>) -> Result<EndlessRun, Response> {
+    let invalid = || make_response!(BadRequest, "Run token is invalid".into());
+
+    let (run_id, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let run_id: i64 = run_id.parse().map_err(|_| invalid())?;
+
+    if !secret.verify(&run_token_message(run_id, username), signature) {
+        return Err(invalid())
+    }
+
+    let row = match retry_busy!(
+        sqlx::query("SELECT Difficulty, StartedAt, CheckpointLevel, CheckpointAt, FinishedAt FROM EndlessRuns WHERE RunID = ?")
+            .bind(run_id)
+            .fetch_optional(&mut **bola_data)
+    ) {
+        Ok(Some(x)) => x,
+        Ok(None) => return Err(invalid()),
+        Err(e) => return Err(e.into_response("reading from EndlessRuns"))
+    };
+
+    Ok(EndlessRun {
+        id: run_id,
+        difficulty: row.get_unchecked("Difficulty"),
+        started_at: row.get_unchecked("StartedAt"),
+        checkpoint_level: row.get_unchecked("CheckpointLevel"),
+        checkpoint_at: row.get_unchecked("CheckpointAt"),
+        finished: row.get_unchecked::<Option<f64>, _>("FinishedAt").is_some()
+    })
+}
+
+
+/// Marks the run as finished with the given number of levels
+///
+/// Returns false if the run was finished by another request in the meantime
+pub(super) async fn finish_run(run: &EndlessRun, levels: u16, now: f64, conn: &mut SqliteConnection) -> Result<bool, DbError> {
+    let result = sqlx::query("UPDATE EndlessRuns SET FinishedAt = ?, Levels = ? WHERE RunID = ? AND FinishedAt IS NULL")
+        .bind(now)
+        .bind(levels)
+        .bind(run.id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+
+#[derive(FromForm)]
+pub struct StartRunRequest {
+    difficulty: Difficulty
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct StartedRun {
+    run_token: String,
+    seed: u32,
+    started_at: f64
+}
+
+
+/// Starts an endless run, which must be checkpointed and then submitted to reach the leaderboard
+#[rocket::post("/runs/endless", data = "<data>")]
+pub async fn start_endless_run(data: Form<StartRunRequest>, user: AuthenticatedUser, secret: &State<ServerSecret>, mut bola_data: Connection<BolaData>) -> Response {
+    let seed = thread_rng().next_u32();
+    let now = UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+
+    let run_id = match retry_busy!(
+        sqlx::query("INSERT INTO EndlessRuns (Username, Difficulty, Seed, StartedAt, CheckpointAt) VALUES (?, ?, ?, ?, ?)")
+            .bind(&user.username)
+            .bind(data.difficulty.0)
+            .bind(seed)
+            .bind(now)
+            .bind(now)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) => r.last_insert_rowid(),
+        Err(e) => return e.into_response("inserting into EndlessRuns")
+    };
+
+    make_response!(Ok, to_string(&StartedRun {
+        run_token: make_run_token(secret, run_id, &user.username),
+        seed,
+        started_at: now
+    }).unwrap())
+}
+
+
+#[derive(FromForm)]
+pub struct CheckpointRequest<'a> {
+    run_token: &'a str,
+    level: u16
+}
+
+
+#[rocket::post("/runs/endless/checkpoint", data = "<data>")]
+pub async fn add_endless_checkpoint(data: Form<CheckpointRequest<'_>>, user: AuthenticatedUser, secret: &State<ServerSecret>, mut bola_data: Connection<BolaData>) -> Response {
+    let run = match load_run(data.run_token, &user.username, secret, &mut bola_data).await {
+        Ok(x) => x,
+        Err(response) => return response
+    };
+    let now = UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+
+    if data.level <= run.checkpoint_level {
+        return make_response!(BadRequest, "Checkpoint must be at a later level".into())
+    }
+    if let Err(reason) = run.check_progress(data.level, now) {
+        return make_response!(BadRequest, reason.into())
+    }
+
+    // Only move forward from the checkpoint that was validated against
+    match retry_busy!(
+        sqlx::query("UPDATE EndlessRuns SET CheckpointLevel = ?, CheckpointAt = ?, CheckpointCount = CheckpointCount + 1 WHERE RunID = ? AND CheckpointLevel = ? AND FinishedAt IS NULL")
+            .bind(data.level)
+            .bind(now)
+            .bind(run.id)
+            .bind(run.checkpoint_level)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() == 0 => make_response!(BadRequest, "Run was updated by another request".into()),
+        Ok(_) => make_response!(Ok, "Checkpoint was recorded".into()),
+        Err(e) => e.into_response("updating checkpoint in EndlessRuns")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(checkpoint_level: u16, checkpoint_at: f64) -> EndlessRun {
+        EndlessRun {
+            id: 1,
+            difficulty: 1,
+            started_at: 0.0,
+            checkpoint_level,
+            checkpoint_at,
+            finished: false
+        }
+    }
+
+    #[test]
+    fn levels_must_take_the_minimum_time() {
+        let min_secs = MIN_SECS_PER_LEVEL[0];
+
+        assert!(run(0, 0.0).check_progress(10, 10.0 * min_secs).is_ok());
+        assert!(run(0, 0.0).check_progress(10, 10.0 * min_secs - 0.1).is_err());
+
+        // Both the levels since the checkpoint and the levels since the start are checked
+        assert!(run(10, 10.0 * min_secs).check_progress(20, 20.0 * min_secs).is_ok());
+        assert!(run(10, 10.0 * min_secs).check_progress(20, 20.0 * min_secs - 0.1).is_err());
+        assert!(run(10, 0.0).check_progress(20, 20.0 * min_secs - 0.1).is_err());
+    }
+
+    #[test]
+    fn checkpoints_must_be_frequent() {
+        let now = 1000.0;
+
+        assert!(run(0, 0.0).check_progress(MAX_LEVELS_PER_CHECKPOINT, now).is_ok());
+        assert_eq!(
+            run(0, 0.0).check_progress(MAX_LEVELS_PER_CHECKPOINT + 1, now),
+            Err("Too many levels since the last checkpoint")
+        );
+    }
+
+    #[test]
+    fn runs_expire() {
+        assert!(run(0, 0.0).check_progress(0, MAX_RUN_SECS).is_ok());
+        assert_eq!(run(0, 0.0).check_progress(0, MAX_RUN_SECS + 1.0), Err("Run has expired"));
+    }
+
+    #[test]
+    fn runs_cannot_go_backwards_or_continue_after_finishing() {
+        assert_eq!(run(5, 100.0).check_progress(4, 1000.0), Err("Run cannot lose levels"));
+
+        let mut finished = run(0, 0.0);
+        finished.finished = true;
+        assert_eq!(finished.check_progress(1, 1000.0), Err("Run is already finished"));
+    }
+}
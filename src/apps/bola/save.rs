@@ -0,0 +1,302 @@
+use std::io::Cursor;
+use std::time::UNIX_EPOCH;
+
+use rocket::async_trait;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::serde::json::to_string;
+use rocket::serde::Serialize;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Row, SqliteConnection};
+
+use super::BolaData;
+use crate::apps::auth::AuthenticatedUser;
+use crate::apps::db::{DbError, begin_immediate, retry_busy};
+use crate::apps::{Response, make_response};
+
+const MAX_KEY_LEN: usize = 64;
+/// In bytes
+const MAX_BLOB_SIZE: usize = 64 * 1024;
+/// In bytes, across all of a player's keys
+const MAX_TOTAL_SIZE: usize = 1024 * 1024;
+const MAX_KEY_COUNT: u32 = 32;
+
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+
+/// The version a client expects a save to be at, from the If-Match header
+pub enum IfMatch {
+    /// The save must not exist yet
+    Absent,
+    /// `*`, the save must exist
+    Any,
+    Version(i64)
+}
+
+
+#[async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let value = match request.headers().get_one("If-Match") {
+            Some(x) => x.trim(),
+            None => return Outcome::Success(Self::Absent)
+        };
+
+        if value == "*" {
+            return Outcome::Success(Self::Any)
+        }
+
+        match value.strip_prefix('"').and_then(|x| x.strip_suffix('"')).and_then(|x| x.parse().ok()) {
+            Some(version) => Outcome::Success(Self::Version(version)),
+            None => {
+                request.local_cache(|| "If-Match must be * or a single quoted version".to_string());
+                Outcome::Failure((Status::BadRequest, ()))
+            }
+        }
+    }
+}
+
+
+/// The current version of a save, as sent to clients
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SaveVersion {
+    version: i64
+}
+
+
+/// Responds to a write that expected another version than `current`
+fn mismatch(current: Option<i64>) -> Response {
+    match current {
+        Some(version) => make_response!(Status::Conflict, to_string(&SaveVersion { version }).unwrap()),
+        None => make_response!(NotFound, "Save does not exist".into())
+    }
+}
+
+
+/// The contents of a save, with its version as the ETag
+pub struct SaveBlob {
+    data: Vec<u8>,
+    version: i64
+}
+
+
+impl<'r> Responder<'r, 'static> for SaveBlob {
+    fn respond_to(self, _request: &'r rocket::Request<'_>) -> response::Result<'static> {
+        rocket::Response::build()
+            .header(ContentType::Binary)
+            .raw_header("ETag", format!("\"{}\"", self.version))
+            .sized_body(self.data.len(), Cursor::new(self.data))
+            .ok()
+    }
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SaveSummary {
+    key: String,
+    version: i64,
+    /// In bytes
+    size: u32,
+    updated_at: f64
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SaveList {
+    saves: Vec<SaveSummary>,
+    /// In bytes
+    total_size: u32,
+    max_total_size: usize,
+    max_blob_size: usize,
+    max_key_count: u32
+}
+
+
+#[rocket::get("/save")]
+pub async fn list_saves(user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
+    let rows = match retry_busy!(
+        sqlx::query("SELECT Key, Version, LENGTH(Data) AS Size, UpdatedAt FROM SaveData WHERE Username = ? ORDER BY Key")
+            .bind(&user.username)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading from SaveData")
+    };
+
+    let saves: Vec<_> = rows
+        .iter()
+        .map(|row| SaveSummary {
+            key: row.get_unchecked("Key"),
+            version: row.get_unchecked("Version"),
+            size: row.get_unchecked("Size"),
+            updated_at: row.get_unchecked("UpdatedAt")
+        })
+        .collect();
+
+    make_response!(Ok, to_string(&SaveList {
+        total_size: saves.iter().map(|save| save.size).sum(),
+        saves,
+        max_total_size: MAX_TOTAL_SIZE,
+        max_blob_size: MAX_BLOB_SIZE,
+        max_key_count: MAX_KEY_COUNT
+    }).unwrap())
+}
+
+
+#[rocket::get("/save/<key>")]
+pub async fn get_save(key: &str, user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Result<SaveBlob, Response> {
+    match retry_busy!(
+        sqlx::query("SELECT Data, Version FROM SaveData WHERE Username = ? AND Key = ?")
+            .bind(&user.username)
+            .bind(key)
+            .fetch_optional(&mut *bola_data)
+    ) {
+        Ok(Some(row)) => Ok(SaveBlob {
+            data: row.get_unchecked("Data"),
+            version: row.get_unchecked("Version")
+        }),
+        Ok(None) => Err(make_response!(NotFound, "Save does not exist".into())),
+        Err(e) => Err(e.into_response("reading from SaveData"))
+    }
+}
+
+
+async fn current_version(username: &str, key: &str, conn: &mut SqliteConnection) -> Result<Option<i64>, DbError> {
+    retry_busy!(
+        sqlx::query("SELECT Version FROM SaveData WHERE Username = ? AND Key = ?")
+            .bind(username)
+            .bind(key)
+            .fetch_optional(&mut *conn)
+    ).map(|row| row.map(|row| row.get_unchecked("Version")))
+}
+
+
+/// Writes a save, which must be at the version given in If-Match
+///
+/// Without If-Match the save is created, and conflicts if it exists. Conflicts respond with the current version
+/// in the same JSON as a successful write
+#[rocket::put("/save/<key>", data = "<data>")]
+pub async fn put_save(key: &str, data: Data<'_>, if_match: IfMatch, user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
+    if !is_valid_key(key) {
+        return make_response!(BadRequest, format!("Key must be 1 to {MAX_KEY_LEN} letters, digits, '_', '-' or '.'"))
+    }
+
+    let data = match data.open(MAX_BLOB_SIZE.bytes()).into_bytes().await {
+        Ok(x) if x.is_complete() => x.into_inner(),
+        Ok(_) => return make_response!(Status::PayloadTooLarge, format!("Save must be at most {MAX_BLOB_SIZE} bytes")),
+        Err(_) => return make_response!(BadRequest, "Could not read save".into())
+    };
+
+    // Quotas are checked in the transaction that writes, so that concurrent writes cannot exceed them together
+    let mut tx = match begin_immediate(&mut bola_data).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response("starting to write save")
+    };
+
+    let measured = sqlx::query("SELECT COUNT(*) AS Keys, TOTAL(LENGTH(Data)) AS Size FROM SaveData WHERE Username = ? AND Key != ?")
+        .bind(&user.username)
+        .bind(key)
+        .fetch_one(&mut tx)
+        .await;
+
+    let row = match measured {
+        Ok(x) => x,
+        Err(e) => return DbError::from(e).into_response("measuring SaveData")
+    };
+
+    if row.get_unchecked::<f64, _>("Size") as usize + data.len() > MAX_TOTAL_SIZE {
+        return make_response!(Status::PayloadTooLarge, format!("Saves must be at most {MAX_TOTAL_SIZE} bytes in total"))
+    }
+
+    let now = UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+
+    let result = match if_match {
+        IfMatch::Absent => {
+            if row.get_unchecked::<u32, _>("Keys") >= MAX_KEY_COUNT {
+                return make_response!(BadRequest, format!("There can be at most {MAX_KEY_COUNT} saves"))
+            }
+
+            sqlx::query("INSERT INTO SaveData (Username, Key, Version, Data, UpdatedAt) VALUES (?, ?, 1, ?, ?) RETURNING Version")
+                .bind(&user.username)
+                .bind(key)
+                .bind(&data)
+                .bind(now)
+                .fetch_optional(&mut tx)
+                .await
+        }
+        IfMatch::Any => {
+            sqlx::query("UPDATE SaveData SET Version = Version + 1, Data = ?, UpdatedAt = ? WHERE Username = ? AND Key = ? RETURNING Version")
+                .bind(&data)
+                .bind(now)
+                .bind(&user.username)
+                .bind(key)
+                .fetch_optional(&mut tx)
+                .await
+        }
+        IfMatch::Version(version) => {
+            sqlx::query("UPDATE SaveData SET Version = Version + 1, Data = ?, UpdatedAt = ? WHERE Username = ? AND Key = ? AND Version = ? RETURNING Version")
+                .bind(&data)
+                .bind(now)
+                .bind(&user.username)
+                .bind(key)
+                .bind(version)
+                .fetch_optional(&mut tx)
+                .await
+        }
+    };
+
+    // The version is the one this write produced, whatever other writers do afterwards
+    let version: i64 = match result.map_err(DbError::from) {
+        Ok(Some(row)) => row.get_unchecked("Version"),
+        Ok(None) | Err(DbError::UniqueViolation { .. }) => return match current_version(&user.username, key, &mut tx).await {
+            Ok(current) => mismatch(current),
+            Err(e) => e.into_response("reading save version")
+        },
+        Err(e) => return e.into_response("writing to SaveData")
+    };
+
+    match tx.commit().await {
+        Ok(_) => make_response!(Ok, to_string(&SaveVersion { version }).unwrap()),
+        Err(e) => DbError::from(e).into_response("committing save")
+    }
+}
+
+
+/// Deletes a save. If-Match is optional here, without it the save is deleted whatever its version
+#[rocket::delete("/save/<key>")]
+pub async fn delete_save(key: &str, if_match: IfMatch, user: AuthenticatedUser, mut bola_data: Connection<BolaData>) -> Response {
+    let result = match if_match {
+        IfMatch::Version(version) => retry_busy!(
+            sqlx::query("DELETE FROM SaveData WHERE Username = ? AND Key = ? AND Version = ?")
+                .bind(&user.username)
+                .bind(key)
+                .bind(version)
+                .execute(&mut *bola_data)
+        ),
+        IfMatch::Absent | IfMatch::Any => retry_busy!(
+            sqlx::query("DELETE FROM SaveData WHERE Username = ? AND Key = ?")
+                .bind(&user.username)
+                .bind(key)
+                .execute(&mut *bola_data)
+        )
+    };
+
+    match result {
+        Ok(r) if r.rows_affected() > 0 => make_response!(Ok, "Save was deleted".into()),
+        Ok(_) => match current_version(&user.username, key, &mut bola_data).await {
+            Ok(current) => mismatch(current),
+            Err(e) => e.into_response("reading save version")
+        },
+        Err(e) => e.into_response("deleting from SaveData")
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use rand::{SeedableRng, rngs::StdRng, RngCore};
+use rocket::{FromForm, State};
+use rocket::form::Form;
+use rocket::serde::json::to_string;
+use rocket::serde::Serialize;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Row, SqliteConnection};
+use rocket_db_pools::sqlx::sqlite::SqliteRow;
+
+use super::{BolaData, achievements, friends, moderation};
+use super::friends::Notification;
+use super::profiles::NOT_PRIVATE;
+use crate::apps::auth::{AuthenticatedUser, AdminUser};
+use crate::apps::db::{DbError, begin_immediate, retry_busy};
+use crate::apps::signing::ServerSecret;
+use crate::apps::{Response, make_response};
+use crate::log::*;
+use crate::ws::WsHub;
+
+const DEFAULT_TOURNAMENT_COUNT: u32 = 10;
+const MAX_TOURNAMENT_COUNT: u32 = 52;
+
+/// Selects the columns `TournamentEvent::from_row` reads. Binds the time at which to check for pauses, twice
+const EVENT_SELECT: &str = "SELECT EventID, Since, Until, LegacySeed, EXISTS (
+        SELECT 1 FROM TournamentPauses
+        WHERE TournamentPauses.EventID = TournamentEvents.EventID AND TournamentPauses.Since <= ? AND TournamentPauses.Until > ?
+    ) AS Paused
+    FROM TournamentEvents";
+
+
+fn unix_now() -> i64 {
+    UNIX_EPOCH.elapsed().unwrap().as_secs() as i64
+}
+
+
+fn start_token_message(event_id: u32, username: &str, issued_at: i64) -> String {
+    format!("tournament-start:{event_id}:{username}:{issued_at}")
+}
+
+
+/// Makes a token proving that the given tournament was handed to `username` at `issued_at`
+///
+/// The username is signed rather than included, like in run tokens, so the token is useless to anyone else
+fn make_start_token(secret: &ServerSecret, event_id: u32, username: &str, issued_at: i64) -> String {
+    format!("{event_id}.{issued_at}.{}", secret.sign(&start_token_message(event_id, username, issued_at)))
+}
+
+
+/// Returns the tournament and time a start token was issued for, if this server issued it to `username`
+fn parse_start_token(secret: &ServerSecret, token: &str, username: &str) -> Option<(u32, i64)> {
+    let mut parts = token.splitn(3, '.');
+    let event_id = parts.next()?.parse().ok()?;
+    let issued_at = parts.next()?.parse().ok()?;
+
+    if secret.verify(&start_token_message(event_id, username, issued_at), parts.next()?) {
+        Some((event_id, issued_at))
+    } else {
+        None
+    }
+}
+
+
+/// A tournament as stored in TournamentEvents
+///
+/// Clients know the id as the tournament's week, which is what it was before tournaments were scheduled
+pub(super) struct TournamentEvent {
+    pub(super) id: u32,
+    since: i64,
+    until: i64,
+    legacy_seed: bool,
+    paused: bool
+}
+
+
+impl TournamentEvent {
+    fn from_row(row: &SqliteRow) -> Self {
+        Self {
+            id: row.get_unchecked("EventID"),
+            since: row.get_unchecked("Since"),
+            until: row.get_unchecked("Until"),
+            legacy_seed: row.get_unchecked("LegacySeed"),
+            paused: row.get_unchecked("Paused")
+        }
+    }
+
+    fn seed(&self, secret: &ServerSecret) -> u32 {
+        if self.legacy_seed {
+            StdRng::seed_from_u64(self.id as u64).next_u32()
+        } else {
+            secret.derive_u32(&format!("tournament-seed:{}", self.id))
+        }
+    }
+
+    fn info(&self, secret: &ServerSecret) -> TournamentInfo {
+        TournamentInfo {
+            week: self.id,
+            seed: self.seed(secret),
+            since: self.since,
+            until: self.until,
+            paused: self.paused
+        }
+    }
+}
+
+
+async fn find_event_at(time: i64, conn: &mut SqliteConnection) -> Result<Option<TournamentEvent>, DbError> {
+    let sql = format!("{EVENT_SELECT} WHERE Cancelled = 0 AND Since <= ? AND Until > ? ORDER BY Since DESC LIMIT 1");
+
+    retry_busy!(
+        sqlx::query(&sql)
+            .bind(time)
+            .bind(time)
+            .bind(time)
+            .bind(time)
+            .fetch_optional(&mut *conn)
+    ).map(|row| row.as_ref().map(TournamentEvent::from_row))
+}
+
+
+/// Finds a tournament that was not cancelled, whether or not it has started
+async fn find_event(id: u32, bola_data: &mut Connection<BolaData>) -> Result<Option<TournamentEvent>, DbError> {
+    let sql = format!("{EVENT_SELECT} WHERE Cancelled = 0 AND EventID = ?");
+    let now = unix_now();
+
+    retry_busy!(
+        sqlx::query(&sql)
+            .bind(now)
+            .bind(now)
+            .bind(id)
+            .fetch_optional(&mut **bola_data)
+    ).map(|row| row.as_ref().map(TournamentEvent::from_row))
+}
+
+
+/// Decides which tournament is running
+///
+/// Tournaments are created by administrators. If none is running and `tournament_length` is configured,
+/// one of that length is scheduled following the previous one
+pub struct TournamentSchedule {
+    auto_length: i64,
+    /// How long after a tournament ends wins are still accepted from players who started it in time
+    grace_period: i64
+}
+
+
+impl TournamentSchedule {
+    pub fn new(tournament_length: u32, grace_period: u32) -> Self {
+        Self {
+            auto_length: tournament_length as i64,
+            grace_period: grace_period as i64
+        }
+    }
+
+    /// Finds the tournament of `week` if a win for it can be recorded right now
+    ///
+    /// That is the case while it runs, and during the grace period after it ends if `start_token`
+    /// shows that `username` fetched the tournament before then
+    pub(super) async fn accepting_event(&self, week: u32, username: &str, start_token: Option<&str>, secret: &ServerSecret, bola_data: &mut Connection<BolaData>) -> Result<Option<TournamentEvent>, DbError> {
+        if let Some(event) = self.current_event(bola_data).await? {
+            if event.id == week {
+                return Ok(Some(event))
+            }
+        }
+
+        let issued_at = match start_token.and_then(|token| parse_start_token(secret, token, username)) {
+            Some((event_id, issued_at)) if event_id == week => issued_at,
+            _ => return Ok(None)
+        };
+        let now = unix_now();
+
+        Ok(find_event(week, bola_data).await?.filter(|event| {
+            event.since <= issued_at && issued_at < event.until && now < event.until + self.grace_period
+        }))
+    }
+
+    pub(super) async fn current_event(&self, conn: &mut SqliteConnection) -> Result<Option<TournamentEvent>, DbError> {
+        let now = unix_now();
+
+        if let Some(event) = find_event_at(now, conn).await? {
+            return Ok(Some(event))
+        }
+        if self.auto_length <= 0 {
+            return Ok(None)
+        }
+
+        // Nothing can be scheduled between choosing the times and inserting, by another request or an administrator
+        let mut tx = begin_immediate(conn).await?;
+
+        if let Some(event) = find_event_at(now, &mut tx).await? {
+            return Ok(Some(event))
+        }
+
+        // Line up with the end of the last tournament so that a fixed length keeps a regular schedule
+        let row = sqlx::query("SELECT MAX(Until) AS LastUntil FROM TournamentEvents WHERE Cancelled = 0 AND Until <= ?")
+            .bind(now)
+            .fetch_one(&mut tx)
+            .await?;
+        let since = match row.get_unchecked::<Option<i64>, _>("LastUntil") {
+            Some(last_until) => last_until + (now - last_until) / self.auto_length * self.auto_length,
+            None => now
+        };
+
+        // Never run into a tournament scheduled by an administrator
+        let row = sqlx::query("SELECT MIN(Since) AS NextSince FROM TournamentEvents WHERE Cancelled = 0 AND Since > ?")
+            .bind(now)
+            .fetch_one(&mut tx)
+            .await?;
+        let until = match row.get_unchecked::<Option<i64>, _>("NextSince") {
+            Some(next_since) => next_since.min(since + self.auto_length),
+            None => since + self.auto_length
+        };
+
+        sqlx::query("INSERT INTO TournamentEvents (Since, Until) VALUES (?, ?)")
+            .bind(since)
+            .bind(until)
+            .execute(&mut tx)
+            .await?;
+
+        let event = find_event_at(now, &mut tx).await?;
+        tx.commit().await?;
+
+        Ok(event)
+    }
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TournamentInfo {
+    week: u32,
+    seed: u32,
+    since: i64,
+    until: i64,
+    /// Wins are not accepted while paused
+    paused: bool
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CurrentTournament {
+    #[serde(flatten)]
+    tournament: TournamentInfo,
+    /// Send back with a win to have it accepted shortly after the tournament ends. Only given to logged in players
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_token: Option<String>
+}
+
+
+#[rocket::get("/tournament")]
+pub async fn get_tournament(user: Option<AuthenticatedUser>, schedule: &State<TournamentSchedule>, secret: &State<ServerSecret>, mut bola_data: Connection<BolaData>) -> Response {
+    match schedule.current_event(&mut bola_data).await {
+        Ok(Some(event)) => make_response!(Ok, to_string(&CurrentTournament {
+            tournament: event.info(secret),
+            start_token: user.map(|user| make_start_token(secret, event.id, &user.username, unix_now()))
+        }).unwrap()),
+        Ok(None) => make_response!(NotFound, "No tournament is running".into()),
+        Err(e) => e.into_response("finding the current tournament")
+    }
+}
+
+
+#[derive(FromForm)]
+pub struct WinTournamentForm {
+    week: u32,
+    /// Older clients do not send a score. Nothing proves it, so it is recorded but never published
+    score: Option<u32>,
+    /// Issued by `get_tournament`
+    start_token: Option<String>
+}
+
+
+#[rocket::post("/tournament", data = "<data>")]
+pub async fn win_tournament(data: Form<WinTournamentForm>, user: AuthenticatedUser, schedule: &State<TournamentSchedule>, secret: &State<ServerSecret>, hub: &State<Arc<WsHub>>, mut bola_data: Connection<BolaData>) -> Response {
+    match moderation::is_banned(&user.username, &mut bola_data).await {
+        Ok(false) => {}
+        Ok(true) => return make_response!(Forbidden, "You are banned from leaderboards".into()),
+        Err(e) => return e.into_response("reading LeaderboardBans")
+    }
+
+    let event = match schedule.accepting_event(data.week, &user.username, data.start_token.as_deref(), secret, &mut bola_data).await {
+        Ok(Some(event)) => event,
+        Ok(None) => return make_response!(BadRequest, "Tournament is not running".into()),
+        Err(e) => return e.into_response("finding the tournament")
+    };
+
+    if event.paused {
+        return make_response!(BadRequest, "Tournament is paused".into())
+    }
+
+    let completed_at = UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+
+    match retry_busy!(
+        sqlx::query("INSERT INTO TournamentWinners (Username, Tournament, CompletedAt, Score) VALUES (?, ?, ?, ?)")
+            .bind(&user.username)
+            .bind(event.id)
+            .bind(completed_at)
+            .bind(data.score.unwrap_or(0))
+            .execute(&mut *bola_data)
+    ) {
+        Ok(_) => {
+            achievements::evaluate(&user.username, hub, &mut bola_data).await;
+            friends::notify_friends(&user.username, Notification::TournamentWin { username: &user.username, week: event.id }, hub, &mut bola_data).await;
+            make_response!(Ok, "Win was recorded".into())
+        }
+        Err(DbError::UniqueViolation { .. }) => make_response!(BadRequest, "Win is already recorded".into()),
+        Err(e) => e.into_response("inserting into TournamentWinners")
+    }
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TournamentResult {
+    /// 1 for the first player to complete the tournament
+    place: u32,
+    username: String,
+    /// Missing for wins recorded before completion times were
+    completed_at: Option<f64>
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TournamentResults {
+    #[serde(flatten)]
+    tournament: TournamentInfo,
+    results: Vec<TournamentResult>
+}
+
+
+/// Lists everyone who completed the tournament of the given week, in the order they completed it
+///
+/// `friends` limits it to the logged in user and their friends
+#[rocket::get("/tournament/<week>/results?<friends>")]
+pub async fn get_tournament_results(week: u32, friends: Option<bool>, viewer: Option<AuthenticatedUser>, secret: &State<ServerSecret>, mut bola_data: Connection<BolaData>) -> Response {
+    let friends_of = match friends::friends_filter(friends, viewer) {
+        Ok(x) => x,
+        Err(response) => return response
+    };
+
+    let event = match find_event(week, &mut bola_data).await {
+        Ok(Some(event)) if event.since <= unix_now() => event,
+        Ok(_) => return make_response!(NotFound, "Tournament does not exist or has not started yet".into()),
+        Err(e) => return e.into_response("finding tournament")
+    };
+
+    let sql = match friends_of {
+        Some(_) => format!("SELECT Username, CompletedAt FROM TournamentWinners WHERE Tournament = ? AND {NOT_PRIVATE} AND {} ORDER BY CompletedAt IS NULL, CompletedAt, Username", friends::FRIENDS_OF),
+        None => format!("SELECT Username, CompletedAt FROM TournamentWinners WHERE Tournament = ? AND {NOT_PRIVATE} ORDER BY CompletedAt IS NULL, CompletedAt, Username")
+    };
+    let query = || {
+        let query = sqlx::query(&sql).bind(week);
+
+        match &friends_of {
+            Some(username) => friends::bind_friends(query, username),
+            None => query
+        }
+    };
+
+    let rows = match retry_busy!(query().fetch_all(&mut *bola_data)) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading from TournamentWinners")
+    };
+
+    let results = rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| TournamentResult {
+            place: i as u32 + 1,
+            username: row.get_unchecked("Username"),
+            completed_at: row.get_unchecked("CompletedAt")
+        })
+        .collect();
+
+    make_response!(Ok, to_string(&TournamentResults {
+        tournament: event.info(secret),
+        results
+    }).unwrap())
+}
+
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TournamentSummary {
+    #[serde(flatten)]
+    tournament: TournamentInfo,
+    /// In the order they completed the tournament
+    winners: Vec<String>
+}
+
+
+/// Lists tournaments that have started, from the most recent one
+///
+/// Pass the `week` of the last tournament received as `before` to get older ones
+#[rocket::get("/tournaments?<before>&<limit>")]
+pub async fn list_tournaments(before: Option<u32>, limit: Option<u32>, secret: &State<ServerSecret>, mut bola_data: Connection<BolaData>) -> Response {
+    let limit = limit.unwrap_or(DEFAULT_TOURNAMENT_COUNT).clamp(1, MAX_TOURNAMENT_COUNT);
+    let now = unix_now();
+    let sql = format!("{EVENT_SELECT} WHERE Cancelled = 0 AND Since <= ? AND EventID < ? ORDER BY Since DESC LIMIT ?");
+
+    let events: Vec<_> = match retry_busy!(
+        sqlx::query(&sql)
+            .bind(now)
+            .bind(now)
+            .bind(now)
+            .bind(before.map(i64::from).unwrap_or(i64::MAX))
+            .bind(limit)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(rows) => rows.iter().map(TournamentEvent::from_row).collect(),
+        Err(e) => return e.into_response("reading from TournamentEvents")
+    };
+
+    let (oldest, newest) = match (events.iter().map(|event| event.id).min(), events.iter().map(|event| event.id).max()) {
+        (Some(oldest), Some(newest)) => (oldest, newest),
+        _ => return make_response!(Ok, "[]".into())
+    };
+
+    let mut indices = HashMap::new();
+    let mut tournaments: Vec<_> = events
+        .iter()
+        .enumerate()
+        .map(|(i, event)| {
+            indices.insert(event.id, i);
+            TournamentSummary {
+                tournament: event.info(secret),
+                winners: Vec::new()
+            }
+        })
+        .collect();
+
+    let winners_sql = format!("SELECT Tournament, Username FROM TournamentWinners WHERE Tournament BETWEEN ? AND ? AND {NOT_PRIVATE} ORDER BY CompletedAt IS NULL, CompletedAt, Username");
+    let rows = match retry_busy!(
+        sqlx::query(&winners_sql)
+            .bind(oldest)
+            .bind(newest)
+            .fetch_all(&mut *bola_data)
+    ) {
+        Ok(x) => x,
+        Err(e) => return e.into_response("reading from TournamentWinners")
+    };
+
+    for row in rows {
+        if let Some(i) = indices.get(&row.get_unchecked::<u32, _>("Tournament")) {
+            tournaments[*i].winners.push(row.get_unchecked("Username"));
+        }
+    }
+
+    make_response!(Ok, to_string(&tournaments).unwrap())
+}
+
+
+#[derive(FromForm)]
+pub struct TimeSpan {
+    /// Unix time in seconds
+    since: i64,
+    /// Unix time in seconds
+    until: i64
+}
+
+
+/// Schedules a tournament, which must not overlap with any other
+#[rocket::post("/tournaments", data = "<data>")]
+pub async fn create_tournament(data: Form<TimeSpan>, admin: AdminUser, mut bola_data: Connection<BolaData>) -> Response {
+    if data.until <= data.since {
+        return make_response!(BadRequest, "Tournament must end after it starts".into())
+    }
+
+    // One statement, so that nothing can be scheduled between the overlap check and the insert
+    match retry_busy!(
+        sqlx::query(
+            "INSERT INTO TournamentEvents (Since, Until, CreatedBy) SELECT ?, ?, ?
+            WHERE NOT EXISTS (SELECT 1 FROM TournamentEvents WHERE Cancelled = 0 AND Since < ? AND Until > ?)"
+        )
+            .bind(data.since)
+            .bind(data.until)
+            .bind(&admin.username)
+            .bind(data.until)
+            .bind(data.since)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() == 0 => make_response!(BadRequest, "Tournament overlaps with another one".into()),
+        Ok(r) => {
+            info!("{} scheduled tournament {}", admin.username, r.last_insert_rowid());
+            make_response!(Ok, format!("{{\"week\": {}}}", r.last_insert_rowid()))
+        }
+        Err(e) => e.into_response("inserting into TournamentEvents")
+    }
+}
+
+
+#[rocket::delete("/tournaments/<week>")]
+pub async fn cancel_tournament(week: u32, admin: AdminUser, mut bola_data: Connection<BolaData>) -> Response {
+    match retry_busy!(
+        sqlx::query("UPDATE TournamentEvents SET Cancelled = 1 WHERE EventID = ? AND Cancelled = 0")
+            .bind(week)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(r) if r.rows_affected() == 0 => make_response!(NotFound, "Tournament does not exist".into()),
+        Ok(_) => {
+            info!("{} cancelled tournament {week}", admin.username);
+            make_response!(Ok, "Tournament was cancelled".into())
+        }
+        Err(e) => e.into_response("cancelling tournament")
+    }
+}
+
+
+/// Stops a tournament from accepting wins for a while
+#[rocket::post("/tournaments/<week>/pauses", data = "<data>")]
+pub async fn pause_tournament(week: u32, data: Form<TimeSpan>, admin: AdminUser, mut bola_data: Connection<BolaData>) -> Response {
+    if data.until <= data.since {
+        return make_response!(BadRequest, "Pause must end after it starts".into())
+    }
+
+    match find_event(week, &mut bola_data).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return make_response!(NotFound, "Tournament does not exist".into()),
+        Err(e) => return e.into_response("finding tournament")
+    }
+
+    match retry_busy!(
+        sqlx::query("INSERT INTO TournamentPauses (EventID, Since, Until) VALUES (?, ?, ?)")
+            .bind(week)
+            .bind(data.since)
+            .bind(data.until)
+            .execute(&mut *bola_data)
+    ) {
+        Ok(_) => {
+            info!("{} paused tournament {week}", admin.username);
+            make_response!(Ok, "Pause was scheduled".into())
+        }
+        Err(e) => e.into_response("inserting into TournamentPauses")
+    }
+}
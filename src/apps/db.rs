@@ -0,0 +1,133 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket_db_pools::sqlx::{self, Connection as _, Sqlite, SqliteConnection, Transaction};
+
+use super::{Response, make_response};
+use crate::log::*;
+
+// Extended result codes from https://www.sqlite.org/rescode.html
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+const SQLITE_CONSTRAINT_FOREIGNKEY: i32 = 787;
+const SQLITE_CONSTRAINT_PRIMARYKEY: i32 = 1555;
+const SQLITE_CONSTRAINT_UNIQUE: i32 = 2067;
+
+pub const MAX_BUSY_RETRIES: u32 = 3;
+/// Multiplied by the attempt number between retries
+pub const BUSY_BACKOFF: Duration = Duration::from_millis(50);
+
+
+/// A database error classified by what a handler may want to do about it
+#[derive(Debug)]
+pub enum DbError {
+    /// A UNIQUE or PRIMARY KEY constraint failed
+    ///
+    /// `constraint` holds the columns SQLite reported, eg. `EndlessLeaderboard.Username, EndlessLeaderboard.Difficulty`
+    UniqueViolation { constraint: Option<String> },
+    ForeignKeyViolation,
+    /// The database stayed busy or locked after retrying
+    Busy,
+    Other(sqlx::Error)
+}
+
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        let db_error = match e.as_database_error() {
+            Some(x) => x,
+            None => return Self::Other(e)
+        };
+
+        let code = match db_error.code().and_then(|code| code.parse::<i32>().ok()) {
+            Some(x) => x,
+            None => return Self::Other(e)
+        };
+
+        match code {
+            SQLITE_CONSTRAINT_PRIMARYKEY | SQLITE_CONSTRAINT_UNIQUE => Self::UniqueViolation {
+                constraint: db_error
+                    .message()
+                    .split_once("constraint failed: ")
+                    .map(|(_, constraint)| constraint.to_string())
+            },
+            SQLITE_CONSTRAINT_FOREIGNKEY => Self::ForeignKeyViolation,
+            // The primary result code is the least significant byte of an extended one
+            code if code & 0xff == SQLITE_BUSY || code & 0xff == SQLITE_LOCKED => Self::Busy,
+            _ => Self::Other(e)
+        }
+    }
+}
+
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UniqueViolation { constraint: Some(constraint) } => write!(f, "unique constraint failed: {constraint}"),
+            Self::UniqueViolation { constraint: None } => write!(f, "unique constraint failed"),
+            Self::ForeignKeyViolation => write!(f, "foreign key constraint failed"),
+            Self::Busy => write!(f, "database is busy"),
+            Self::Other(e) => write!(f, "{e}")
+        }
+    }
+}
+
+
+impl std::error::Error for DbError {}
+
+
+impl DbError {
+    /// Turns an error the handler has no specific answer for into a response
+    ///
+    /// Anything other than the database being busy is a bug, and gets logged with `context`
+    pub fn into_response(self, context: &str) -> Response {
+        match self {
+            Self::Busy => make_response!(Status::ServiceUnavailable, "The server is busy. Please try again".into()),
+            e => {
+                default_error!(
+                    e,
+                    "{context}"
+                );
+                make_response!(BUG)
+            }
+        }
+    }
+}
+
+
+/// Begins a transaction that holds the write lock from the start, like `BEGIN IMMEDIATE`
+///
+/// sqlx only issues a deferred `BEGIN`, which lets another writer commit between the transaction's reads and its
+/// first write. An empty write takes the lock up front instead, and the transaction still rolls back when dropped
+pub async fn begin_immediate(conn: &mut SqliteConnection) -> Result<Transaction<'_, Sqlite>, DbError> {
+    let mut tx = conn.begin().await?;
+
+    // _Migrations exists in every database
+    sqlx::query("DELETE FROM _Migrations WHERE 0")
+        .execute(&mut tx)
+        .await?;
+
+    Ok(tx)
+}
+
+
+/// Awaits the sqlx future produced by `$query`, producing `Result<_, DbError>`
+///
+/// `$query` is evaluated again on every retry, so it must not move anything it binds
+macro_rules! retry_busy {
+    ($query: expr) => {{
+        let mut attempt = 0;
+        loop {
+            match $query.await.map_err($crate::apps::db::DbError::from) {
+                Err($crate::apps::db::DbError::Busy) if attempt < $crate::apps::db::MAX_BUSY_RETRIES => {
+                    attempt += 1;
+                    rocket::tokio::time::sleep($crate::apps::db::BUSY_BACKOFF * attempt).await;
+                }
+                result => break result
+            }
+        }
+    }};
+}
+
+pub(crate) use retry_busy;
@@ -0,0 +1,206 @@
+use std::time::UNIX_EPOCH;
+
+use rocket::fairing::AdHoc;
+use rocket_db_pools::Database;
+use rocket_db_pools::sqlx::{self, Row, SqlitePool};
+
+use crate::log::*;
+
+
+/// A versioned schema change embedded into the binary
+///
+/// Versions must be unique within a database and are applied in ascending order
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str
+}
+
+
+const CREATE_MIGRATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS _Migrations (
+    Version INTEGER PRIMARY KEY NOT NULL,
+    Name TEXT NOT NULL,
+    AppliedAt REAL NOT NULL
+)";
+
+
+async fn applied_migrations(pool: &SqlitePool) -> Result<Vec<(u32, f64)>, sqlx::Error> {
+    sqlx::query(CREATE_MIGRATIONS_TABLE)
+        .execute(pool)
+        .await?;
+
+    Ok(
+        sqlx::query("SELECT Version, AppliedAt FROM _Migrations ORDER BY Version")
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| (row.get_unchecked("Version"), row.get_unchecked("AppliedAt")))
+            .collect()
+    )
+}
+
+
+/// Applies every migration that has not been applied yet, each in its own transaction
+///
+/// Returns the migrations that were applied
+pub async fn run_migrations(pool: &SqlitePool, migrations: &'static [Migration]) -> Result<Vec<&'static Migration>, sqlx::Error> {
+    let applied = applied_migrations(pool).await?;
+    let mut pending: Vec<_> = migrations
+        .iter()
+        .filter(|migration| !applied.iter().any(|(version, _)| *version == migration.version))
+        .collect();
+
+    pending.sort_by_key(|migration| migration.version);
+
+    for migration in pending.iter() {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query(migration.sql)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("INSERT INTO _Migrations (Version, Name, AppliedAt) VALUES (?, ?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(UNIX_EPOCH.elapsed().unwrap().as_secs_f64().round())
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+    }
+
+    Ok(pending)
+}
+
+
+/// Describes which migrations have been applied to the given database, in a human readable form
+pub async fn migration_status(db_name: &str, pool: &SqlitePool, migrations: &[Migration]) -> String {
+    let applied = match applied_migrations(pool).await {
+        Ok(x) => x,
+        Err(e) => return format!("{db_name}: could not read migrations: {e}")
+    };
+
+    let mut out = format!("{db_name}:");
+
+    for migration in migrations {
+        match applied.iter().find(|(version, _)| *version == migration.version) {
+            Some((_, applied_at)) => out += &format!("\n  {:04} {} (applied at {applied_at})", migration.version, migration.name),
+            None => out += &format!("\n  {:04} {} (pending)", migration.version, migration.name)
+        }
+    }
+
+    for (version, applied_at) in applied {
+        if !migrations.iter().any(|migration| migration.version == version) {
+            out += &format!("\n  {version:04} unknown to this build (applied at {applied_at})");
+        }
+    }
+
+    out
+}
+
+
+/// Makes a fairing that migrates the given database on ignite, aborting launch if it fails
+///
+/// Must be attached after the database's own fairing
+pub fn fairing<D: Database<Pool = SqlitePool>>(migrations: &'static [Migration]) -> AdHoc {
+    AdHoc::try_on_ignite("Run Migrations", move |rocket| async move {
+        let pool = match D::fetch(&rocket) {
+            Some(db) => (**db).clone(),
+            None => {
+                error!("{} database was not initialized before migrating", D::NAME);
+                return Err(rocket)
+            }
+        };
+
+        match run_migrations(&pool, migrations).await {
+            Ok(applied) => {
+                for migration in applied {
+                    info!("Applied migration {:04} {} to {}", migration.version, migration.name, D::NAME);
+                }
+                Ok(rocket)
+            }
+            Err(e) => {
+                default_error!(
+                    e,
+                    "migrating {}",
+                    D::NAME
+                );
+                Err(rocket)
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket_db_pools::sqlx::sqlite::SqlitePoolOptions;
+
+    /// Every connection to an in-memory database opens a new one, so the pool is kept to one
+    async fn memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    async fn tables(pool: &SqlitePool) -> Vec<String> {
+        sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get_unchecked("name"))
+            .collect()
+    }
+
+    async fn check_bootstrap(migrations: &'static [Migration], expected_tables: &[&str]) {
+        let pool = memory_pool().await;
+
+        let applied = run_migrations(&pool, migrations).await.unwrap();
+        assert_eq!(applied.len(), migrations.len());
+
+        let recorded: Vec<u32> = applied_migrations(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(version, _)| version)
+            .collect();
+        let mut versions: Vec<u32> = migrations.iter().map(|migration| migration.version).collect();
+        versions.sort();
+        assert_eq!(recorded, versions);
+
+        let tables = tables(&pool).await;
+        for table in expected_tables {
+            assert!(tables.iter().any(|name| name == table), "{table} was not created");
+        }
+
+        assert!(run_migrations(&pool, migrations).await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn bola_migrations_bootstrap_an_empty_database() {
+        check_bootstrap(crate::apps::bola::MIGRATIONS, &[
+            "_Migrations",
+            "EndlessLeaderboard",
+            "TournamentWinners",
+            "EndlessRuns",
+            "WindowedLeaderboard",
+            "TournamentEvents",
+            "TournamentPauses",
+            "PlayerSettings",
+            "UserAchievements",
+            "SaveData",
+            "Friendships",
+            "LeaderboardBans",
+            "ModerationLog"
+        ]).await;
+    }
+
+    #[rocket::async_test]
+    async fn auth_migrations_bootstrap_an_empty_database() {
+        check_bootstrap(crate::apps::auth::MIGRATIONS, &["_Migrations", "PasswordUsers"]).await;
+    }
+}
@@ -4,6 +4,9 @@ use rocket::State;
 pub mod auth;
 pub mod blog;
 pub mod bola;
+pub mod db;
+pub mod migrations;
+pub mod signing;
 
 pub const BUG_MESSAGE: &str = "We encountered a bug on our end. Please try again later";
 
@@ -0,0 +1,118 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shorter secrets can be guessed, which would let anyone forge tokens
+pub const MIN_SECRET_LEN: usize = 32;
+
+
+/// Signs and verifies values handed to clients with a secret only the server knows
+pub struct ServerSecret {
+    key: Vec<u8>
+}
+
+
+impl ServerSecret {
+    /// Returns None if the secret is shorter than `MIN_SECRET_LEN`
+    pub fn new(secret: &str) -> Option<Self> {
+        if secret.len() < MIN_SECRET_LEN {
+            return None
+        }
+
+        Some(Self {
+            key: secret.as_bytes().to_vec()
+        })
+    }
+
+    fn mac(&self, message: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.key.as_slice())
+            .expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+        mac
+    }
+
+    /// Returns the hex encoded signature of `message`
+    pub fn sign(&self, message: &str) -> String {
+        self.mac(message)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Derives a number from `message` that cannot be predicted without the secret
+    pub fn derive_u32(&self, message: &str) -> u32 {
+        let bytes = self.mac(message).finalize().into_bytes();
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// Checks a signature made by `sign` in constant time
+    pub fn verify(&self, message: &str, signature: &str) -> bool {
+        let bytes = match decode_hex(signature) {
+            Some(x) => x,
+            None => return false
+        };
+
+        self.mac(message).verify_slice(bytes.as_slice()).is_ok()
+    }
+}
+
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret() -> ServerSecret {
+        ServerSecret::new(&"s".repeat(MIN_SECRET_LEN)).unwrap()
+    }
+
+    #[test]
+    fn short_secrets_are_refused() {
+        assert!(ServerSecret::new(&"s".repeat(MIN_SECRET_LEN - 1)).is_none());
+    }
+
+    #[test]
+    fn signatures_verify() {
+        let secret = secret();
+        let signature = secret.sign("endless-run:1:alice");
+
+        assert!(secret.verify("endless-run:1:alice", &signature));
+        assert!(!secret.verify("endless-run:1:bob", &signature));
+        assert!(!ServerSecret::new(&"t".repeat(MIN_SECRET_LEN)).unwrap().verify("endless-run:1:alice", &signature));
+    }
+
+    #[test]
+    fn tampered_signatures_are_refused() {
+        let secret = secret();
+        let signature = secret.sign("endless-run:1:alice");
+        let last = if signature.ends_with('0') { "1" } else { "0" };
+        let tampered = format!("{}{last}", &signature[..signature.len() - 1]);
+
+        assert!(!secret.verify("endless-run:1:alice", &tampered));
+        assert!(!secret.verify("endless-run:1:alice", &format!("g{}", &signature[1..])));
+    }
+
+    #[test]
+    fn truncated_signatures_are_refused() {
+        let secret = secret();
+        let signature = secret.sign("endless-run:1:alice");
+
+        assert!(!secret.verify("endless-run:1:alice", &signature[..signature.len() - 2]));
+        assert!(!secret.verify("endless-run:1:alice", &signature[..signature.len() - 1]));
+        assert!(!secret.verify("endless-run:1:alice", ""));
+    }
+}
@@ -7,9 +7,9 @@ extern crate mangle_rust_utils;
 extern crate rocket;
 
 use std::fs::read_to_string;
+use std::sync::Arc;
 use std::time::Duration;
 
-use once_cell::sync::OnceCell;
 use rocket::http::Status;
 use rocket::{catchers};
 use rocket::shield::{Hsts, Shield, XssFilter, Referrer};
@@ -19,9 +19,9 @@ use rocket::serde::Deserialize;
 use rocket_cors::CorsOptions;
 use simple_logger::formatters::default_format;
 
-use apps::auth::{get_session_with_password, make_user, remove_session, renew_session};
+use apps::auth::{get_session_with_password, get_session_with_json, make_user, make_user_with_json, remove_session, renew_session};
 use mangle_detached_console::{ConsoleServer, send_message, ConsoleSendError};
-use clap::Command;
+use clap::{Arg, Command};
 
 use rocket_db_pools::Database;
 
@@ -42,11 +42,12 @@ mod log {
 
 
 use log::LOG;
-use tokio_tungstenite::tungstenite::http::{Response, StatusCode};
 
-use crate::ws::WsServer;
 
-static BOLA_DB_URL: OnceCell<String> = OnceCell::new();
+/// Keeps the weekly tournaments that ran before they could be scheduled
+fn default_tournament_length() -> u32 {
+	7 * 24 * 3600
+}
 
 
 #[derive(Deserialize, Clone)]
@@ -63,9 +64,45 @@ struct AppConfig {
 	failed_logins_path: String,
 	cleanup_interval: u32,
 	password_hash_length: u8,
-	ws_port: u16,
 	ws_ping_interval: u32,
-	max_session_renewals: u8
+	/// Seconds after a ping interval that a WebSocket has to answer the ping before it is dropped.
+	/// 0 uses ws_ping_interval, as pongs arriving right at the next ping would otherwise race it
+	#[serde(default)]
+	ws_pong_timeout: u32,
+	/// Messages that can wait to be sent to one WebSocket. 0 uses ws::DEFAULT_QUEUE_CAPACITY
+	#[serde(default)]
+	ws_queue_capacity: u32,
+	/// What to do with a WebSocket whose queue is full, drop_oldest or disconnect
+	#[serde(default)]
+	ws_slow_consumer_policy: ws::SlowConsumerPolicy,
+	max_session_renewals: u8,
+	/// Key used to sign tokens handed out to clients, at least apps::signing::MIN_SECRET_LEN bytes long.
+	/// Launch is aborted while it is missing or too short
+	#[serde(default)]
+	server_secret: String,
+	/// Length in seconds of tournaments scheduled when none is running, a week unless set.
+	/// 0 only runs tournaments created by admins
+	#[serde(default = "default_tournament_length")]
+	tournament_length: u32,
+	/// Seconds after a tournament ends during which wins from players who started it in time are accepted
+	#[serde(default)]
+	tournament_grace_period: u32,
+	/// Usernames allowed to manage tournaments. Admins are moderators as well
+	#[serde(default)]
+	admins: Vec<String>,
+	/// Usernames allowed to moderate leaderboards
+	#[serde(default)]
+	moderators: Vec<String>,
+	/// Origins that browsers may open WebSockets from. Empty allows any
+	#[serde(default)]
+	ws_allowed_origins: Vec<String>,
+	/// Most WebSockets that can be open at once. 0 is no limit
+	#[serde(default)]
+	ws_max_connections: u32,
+	/// Most WebSockets that one address can have open at once. 0 is no limit.
+	/// Counted by the address of the peer, so behind a reverse proxy every client shares the proxy's and this should be 0
+	#[serde(default)]
+	ws_max_connections_per_ip: u32
 }
 
 
@@ -114,6 +151,67 @@ async fn main() {
 		.subcommand(
 			Command::new("stop")
 				.about("Stops the currently running server")
+		)
+		.subcommand(
+			Command::new("migrate")
+				.about("Inspects the database migrations of the running server")
+				.subcommand_required(true)
+				.subcommand(
+					Command::new("status")
+						.about("Lists applied and pending migrations for every database")
+				)
+		)
+		.subcommand(
+			Command::new("moderate")
+				.about("Moderates the Bola leaderboards")
+				.subcommand_required(true)
+				.subcommand(
+					Command::new("hide")
+						.about("Hides a player's entries on a difficulty")
+						.arg(Arg::new("username").required(true))
+						.arg(Arg::new("difficulty").required(true))
+						.arg(Arg::new("reason").required(true).num_args(1..))
+				)
+				.subcommand(
+					Command::new("unhide")
+						.about("Shows a player's hidden entries on a difficulty")
+						.arg(Arg::new("username").required(true))
+						.arg(Arg::new("difficulty").required(true))
+						.arg(Arg::new("reason").required(true).num_args(1..))
+				)
+				.subcommand(
+					Command::new("remove")
+						.about("Deletes a player's entries on a difficulty")
+						.arg(Arg::new("username").required(true))
+						.arg(Arg::new("difficulty").required(true))
+						.arg(Arg::new("reason").required(true).num_args(1..))
+				)
+				.subcommand(
+					Command::new("ban")
+						.about("Stops a player from submitting runs and tournament wins")
+						.arg(Arg::new("username").required(true))
+						.arg(Arg::new("reason").required(true).num_args(1..))
+				)
+				.subcommand(
+					Command::new("unban")
+						.about("Lifts a player's ban")
+						.arg(Arg::new("username").required(true))
+						.arg(Arg::new("reason").required(true).num_args(1..))
+				)
+				.subcommand(
+					Command::new("log")
+						.about("Lists the most recent moderation actions")
+						.arg(Arg::new("limit"))
+				)
+		)
+		.subcommand(
+			Command::new("ws")
+				.about("Inspects the WebSockets of the running server")
+				.subcommand_required(true)
+				.subcommand(
+					Command::new("stats")
+						.about("Counts open, accepted and refused WebSockets")
+				)
 		);
 	
 	let args: Vec<String> = std::env::args().collect();
@@ -162,7 +260,9 @@ async fn main() {
 	let built = rocket::build()
 		.mount("/api", rocket::routes![
 			get_session_with_password,
+			get_session_with_json,
 			make_user,
+			make_user_with_json,
 			remove_session,
 			renew_session,
 			apps::blog::get_blogs,
@@ -172,7 +272,35 @@ async fn main() {
 			apps::bola::get_tournament,
 			apps::bola::win_tournament,
 			apps::bola::add_leaderboard_entry,
-			apps::bola::get_account
+			apps::bola::get_account,
+			apps::bola::start_endless_run,
+			apps::bola::add_endless_checkpoint,
+			apps::bola::get_leaderboard_page,
+			apps::bola::get_leaderboard_rank,
+			apps::bola::get_tournament_results,
+			apps::bola::list_tournaments,
+			apps::bola::create_tournament,
+			apps::bola::cancel_tournament,
+			apps::bola::pause_tournament,
+			apps::bola::get_player_profile,
+			apps::bola::set_privacy,
+			apps::bola::get_achievements,
+			apps::bola::get_player_achievements,
+			apps::bola::list_saves,
+			apps::bola::get_save,
+			apps::bola::put_save,
+			apps::bola::delete_save,
+			apps::bola::list_friends,
+			apps::bola::send_friend_request,
+			apps::bola::accept_friend_request,
+			apps::bola::decline_friend_request,
+			apps::bola::remove_friend,
+			apps::bola::hide_entry,
+			apps::bola::unhide_entry,
+			apps::bola::remove_leaderboard_entry,
+			apps::bola::ban_player,
+			apps::bola::unban_player,
+			apps::bola::get_moderation_log
 		])
 		.register("/", catchers![default_catcher])
 		.attach(AdHoc::config::<AppConfig>())
@@ -199,8 +327,59 @@ async fn main() {
 			let state = apps::auth::make_auth_state(rocket.state::<AppConfig>().unwrap());
 			rocket.manage(state)
 		}))
+		.attach(AdHoc::try_on_ignite("Load Server Secret", |rocket| async {
+			match apps::signing::ServerSecret::new(rocket.state::<AppConfig>().unwrap().server_secret.as_str()) {
+				Some(secret) => Ok(rocket.manage(secret)),
+				None => {
+					error!(
+						"server_secret must be set to at least {} random bytes, eg. from `openssl rand -hex 32`",
+						apps::signing::MIN_SECRET_LEN
+					);
+					Err(rocket)
+				}
+			}
+		}))
+		.attach(AdHoc::on_ignite("Build Tournament Schedule", |rocket| async {
+			let config = rocket.state::<AppConfig>().unwrap();
+			let schedule = apps::bola::TournamentSchedule::new(config.tournament_length, config.tournament_grace_period);
+			rocket.manage(schedule)
+		}))
 		.attach(apps::bola::BolaData::init())
+		.attach(apps::migrations::fairing::<apps::bola::BolaData>(apps::bola::MIGRATIONS))
 		.attach(apps::auth::Credentials::init())
+		.attach(apps::migrations::fairing::<apps::auth::Credentials>(apps::auth::MIGRATIONS))
+		.attach(AdHoc::on_ignite("Mount WebSockets", |rocket| async {
+			// Handlers outlive requests, so they are given state up front instead of through guards
+			let config = rocket.state::<AppConfig>().unwrap().clone();
+
+			// Set before the hub starts pinging
+			ws::PING_INTERVAL.set(Duration::from_secs(config.ws_ping_interval as u64))
+				.expect("Could not set PING_INTERVAL");
+			ws::PONG_TIMEOUT.set(Duration::from_secs(match config.ws_pong_timeout {
+				0 => config.ws_ping_interval,
+				timeout => timeout
+			} as u64))
+				.expect("Could not set PONG_TIMEOUT");
+			ws::QUEUE_CAPACITY.set(match config.ws_queue_capacity {
+				0 => ws::DEFAULT_QUEUE_CAPACITY,
+				capacity => capacity as usize
+			})
+				.expect("Could not set QUEUE_CAPACITY");
+			ws::SLOW_CONSUMER_POLICY.set(config.ws_slow_consumer_policy)
+				.expect("Could not set SLOW_CONSUMER_POLICY");
+
+			let context = Arc::new(ws::WsContext {
+				bola_data: (**apps::bola::BolaData::fetch(&rocket).unwrap()).clone(),
+				auth: rocket.state::<apps::auth::AuthState>().unwrap().clone(),
+				limiter: Arc::new(ws::WsLimiter::new(config.ws_max_connections, config.ws_max_connections_per_ip)),
+				hub: Arc::new(ws::WsHub::new()),
+				config
+			});
+			rocket
+				.mount("/ws/bola", apps::bola::ws_routes(&context))
+				.manage(context.hub.clone())
+				.manage(context)
+		}))
 		.attach(Shield::default()
 			.enable(Hsts::default())
 			.enable(XssFilter::default())
@@ -249,38 +428,10 @@ async fn main() {
 		bad_exit!()
 	}
 
-	let _ = BOLA_DB_URL.set(
-		db_config
-			.get("bola_data")
-			.unwrap()
-			.clone()
-			.find("url")
-			.unwrap()
-			.into_string()
-			.unwrap()
-	);
+	let bola_pool = (**apps::bola::BolaData::fetch(&ignited).unwrap()).clone();
+	let credentials_pool = (**apps::auth::Credentials::fetch(&ignited).unwrap()).clone();
 
-	let app_config = ignited.state::<AppConfig>().unwrap();
-
-	ws::PING_INTERVAL.set(Duration::from_secs(app_config.ws_ping_interval as u64))
-		.expect("Could not set PING_INTERVAL");
-
-	let ws_server = unwrap_result_or_default_error!(
-		WsServer::bind(
-			app_config.ws_port,
-			|req, response| {
-				match req.uri().path() {
-					"/ws/bola/leaderboards" => Ok((response, apps::bola::accept_leaderboard_ws)),
-					_ => {
-						let mut response = Response::new(Some("No WS endpoint at the given uri".into()));
-						*response.status_mut() = StatusCode::NOT_FOUND;
-						return Err(response)
-					}
-				}
-			}
-		).await,
-		"starting Bola Websocket server"
-	);
+	let ws_context = ignited.state::<Arc<ws::WsContext>>().unwrap().clone();
 
 	let mut console_server = unwrap_result_or_default_error!(
 		ConsoleServer::bind(pipe_addr.as_os_str()),
@@ -338,6 +489,28 @@ async fn main() {
 
 				match matches.subcommand().unwrap() {
 					("status", _) => write_all!("Server is good!"),
+					("migrate", sub_matches) => match sub_matches.subcommand().unwrap() {
+						("status", _) => {
+							let status = [
+								apps::migrations::migration_status("credentials", &credentials_pool, apps::auth::MIGRATIONS).await,
+								apps::migrations::migration_status("bola_data", &bola_pool, apps::bola::MIGRATIONS).await
+							].join("\n");
+							write_all!(status.as_str())
+						}
+						(cmd, _) => {
+							error!("Received the following migrate command from client console: {cmd}");
+						}
+					}
+					("moderate", sub_matches) => {
+						let msg = apps::bola::moderation_console_command(sub_matches, &ws_context.hub, &bola_pool).await;
+						write_all!(msg.as_str())
+					}
+					("ws", sub_matches) => match sub_matches.subcommand().unwrap() {
+						("stats", _) => write_all!(ws_context.limiter.stats().as_str()),
+						(cmd, _) => {
+							error!("Received the following ws command from client console: {cmd}");
+						}
+					}
 					("stop", _) => {
 						final_event = Some(event);
 						warn!("Stop command issued");
@@ -349,7 +522,6 @@ async fn main() {
 				}
 			}
 		} => {}
-		_ = ws_server.start() => {}
 	};
 
 	if let Some(mut event) = final_event {
@@ -1,150 +1,1195 @@
-use std::{io::Error, sync::{Arc}, mem::replace, ops::{DerefMut, Deref}, time::Duration};
+use std::{collections::{HashMap, HashSet, VecDeque}, future::Future, io, net::IpAddr, pin::Pin, sync::{Arc, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}}, task::{Context, Poll}, time::{Duration, Instant}};
 
+use dashmap::DashMap;
 use once_cell::sync::OnceCell;
-use rocket::{tokio::{net::{TcpStream, TcpListener}, task::JoinHandle, spawn, time::sleep, sync::Mutex}, futures::SinkExt};
-use tokio_tungstenite::{WebSocketStream, accept_hdr_async, tungstenite::{Message, handshake::server::{Callback, Request, Response, ErrorResponse}}};
+use rocket::{async_trait, Data, Request, Response};
+use rocket::data::{IoHandler, IoStream};
+use rocket::http::{Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::route::{self, Handler, Route};
+use rocket::serde::Deserialize;
+use rocket::{tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, task::JoinHandle, spawn, time::{sleep, timeout}, sync::{Notify, mpsc}}, futures::{SinkExt, StreamExt, future::BoxFuture}};
+use rocket_db_pools::sqlx::SqlitePool;
+use tokio_tungstenite::{WebSocketStream, tungstenite::{Message, handshake::derive_accept_key, protocol::Role}};
 
-use crate::log::*;
+use crate::apps::auth::{AuthState, AuthenticatedUser};
+use crate::AppConfig;
 
+/// Clients authenticating during the handshake offer a subprotocol made of this and their session key
+const SESSION_PROTOCOL_PREFIX: &str = "session.";
+/// Offered alongside the session key, and accepted in its place so that the key is never sent back
+const SESSION_PROTOCOL: &str = "session";
+/// How long a client that did not authenticate during the handshake has to send its session key
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub type WebSocket = WebSocketStream<TcpStream>;
 
+pub type WebSocket = WebSocketStream<TrackedStream>;
 
-pub struct WsServer {
-    listener: TcpListener,
-    verifier: CallbackFn
+
+/// Application state that apps capture in their WebSocket handlers, which outlive the request that opened them
+pub struct WsContext {
+    pub bola_data: SqlitePool,
+    pub auth: AuthState,
+    pub limiter: Arc<WsLimiter>,
+    pub hub: Arc<WsHub>,
+    pub(crate) config: AppConfig
 }
 
 
-type CallbackFn = fn(&Request, Response) -> Result<(Response, fn(WebSocket)), ErrorResponse>;
+fn exceeds(count: usize, max: usize) -> bool {
+    max > 0 && count >= max
+}
 
 
-impl WsServer {
-    pub async fn bind(port: u16, verifier: CallbackFn) -> Result<Self, Error> {
-        Ok(
-            Self {
-                listener: TcpListener::bind(format!("0.0.0.0:{port}")).await?,
-                verifier
-            }
+/// Caps WebSocket connections and counts them for monitoring. Limits of 0 are no limit
+#[derive(Default)]
+pub struct WsLimiter {
+    max_connections: usize,
+    max_per_ip: usize,
+    /// Connections that are open or about to be
+    connections: AtomicUsize,
+    per_ip: DashMap<IpAddr, usize>,
+    accepted: AtomicU64,
+    refused: AtomicU64
+}
+
+
+impl WsLimiter {
+    pub fn new(max_connections: u32, max_per_ip: u32) -> Self {
+        Self {
+            max_connections: max_connections as usize,
+            max_per_ip: max_per_ip as usize,
+            ..Default::default()
+        }
+    }
+
+    /// Counts a connection from `ip`, or gives the status and reason it was refused with
+    fn admit(self: &Arc<Self>, ip: Option<IpAddr>) -> Result<WsPermit, (Status, &'static str)> {
+        // Dropping the permit undoes the counting if the connection is refused
+        let mut permit = WsPermit {
+            limiter: self.clone(),
+            ip: None
+        };
+        let connections = self.connections.fetch_add(1, Ordering::Relaxed);
+        let from_ip = ip.map(|ip| {
+            permit.ip = Some(ip);
+            let mut count = self.per_ip.entry(ip).or_insert(0);
+            *count += 1;
+            *count - 1
+        });
+
+        let refusal = if exceeds(connections, self.max_connections) {
+            (Status::ServiceUnavailable, "Too many WebSocket connections")
+        } else if from_ip.map_or(false, |count| exceeds(count, self.max_per_ip)) {
+            (Status::TooManyRequests, "Too many WebSocket connections from this address")
+        } else {
+            self.accepted.fetch_add(1, Ordering::Relaxed);
+            return Ok(permit)
+        };
+
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        Err(refusal)
+    }
+
+    /// Describes the current and lifetime counts, for the console
+    pub fn stats(&self) -> String {
+        format!(
+            "Open: {}\nAddresses: {}\nAccepted: {}\nRefused: {}",
+            self.connections.load(Ordering::Relaxed),
+            self.per_ip.len(),
+            self.accepted.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed)
         )
     }
+}
+
+
+/// Holds a connection's place in the `WsLimiter` counts until dropped
+struct WsPermit {
+    limiter: Arc<WsLimiter>,
+    ip: Option<IpAddr>
+}
+
 
-    pub async fn start(&self) -> ! {
-        struct WsCallback<'a> {
-            callback_fn: CallbackFn,
-            handler: &'a mut Option<fn(WebSocket)>
+impl Drop for WsPermit {
+    fn drop(&mut self) {
+        self.limiter.connections.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(ip) = self.ip {
+            // Addresses are forgotten once they have no connections, so that the map does not keep growing
+            self.limiter.per_ip.remove_if_mut(&ip, |_, count| {
+                *count -= 1;
+                *count == 0
+            });
         }
+    }
+}
 
-        impl<'a> Callback for WsCallback<'a> {
-            fn on_request(
-                self,
-                request: &Request,
-                response: Response,
-            ) -> Result<Response, ErrorResponse> {
-                (self.callback_fn)(request, response)
-                    .map(|ok| {
-                        *self.handler = Some(ok.1);
-                        ok.0
-                    })
-            }
+
+/// The upgraded connection, which keeps its place in the `WsLimiter` counts for as long as the socket exists
+pub struct TrackedStream {
+    io: IoStream,
+    _permit: WsPermit
+}
+
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+
+/// An accepted WebSocket, handed to the handler of the route it connected through
+pub struct WsConnection {
+    pub stream: WebSocket,
+    /// Whoever sent a session key during the handshake
+    pub user: Option<AuthenticatedUser>,
+    /// Values of the `<name>` segments in the path of the route
+    pub params: HashMap<String, String>,
+    /// The subprotocol agreed on with the client, out of those the route speaks
+    pub protocol: Option<String>
+}
+
+
+type BoxedHandler = Arc<dyn Fn(WsConnection) -> BoxFuture<'static, ()> + Send + Sync>;
+
+
+/// Finds the session key offered as a subprotocol during a handshake
+///
+/// Keys are not read from the query, as request URIs end up in logs
+fn find_session_key<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    offered_protocols(request).find_map(|protocol| protocol.strip_prefix(SESSION_PROTOCOL_PREFIX))
+}
+
+
+fn offered_protocols<'r>(request: &'r Request<'_>) -> impl Iterator<Item = &'r str> {
+    request
+        .headers()
+        .get("Sec-WebSocket-Protocol")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+
+/// Authenticates a client by its first message, for clients that did not send a session key during the handshake
+async fn authenticate_by_message(stream: &mut WebSocket, auth: &AuthState) -> Option<AuthenticatedUser> {
+    match timeout(AUTH_TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(Message::Text(key)))) => auth.authenticate(&key),
+        _ => None
+    }
+}
+
+
+/// Whoever is behind a connection, which must send its session key first if it did not during the handshake
+///
+/// Tells the client when it could not be authenticated
+pub async fn require_user(stream: &mut WebSocket, user: Option<AuthenticatedUser>, auth: &AuthState) -> Option<AuthenticatedUser> {
+    let user = match user {
+        Some(x) => Some(x),
+        None => authenticate_by_message(stream, auth).await
+    };
+
+    if user.is_none() {
+        let _ = stream.send(Message::Text("Session key is either invalid or expired".into())).await;
+    }
+    user
+}
+
+
+/// A request to switch to a WebSocket, along with who sent it
+struct WsUpgrade {
+    accept_key: String,
+    /// Whether the client authenticated with a session key, so `SESSION_PROTOCOL` is accepted when no other is
+    session_protocol: bool,
+    user: Option<AuthenticatedUser>
+}
+
+
+#[async_trait]
+impl<'r> FromRequest<'r> for WsUpgrade {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        if !headers.get("Upgrade").any(|x| x.eq_ignore_ascii_case("websocket")) {
+            request.local_cache(|| "Expected a WebSocket upgrade".to_string());
+            return Outcome::Failure((Status::UpgradeRequired, ()))
         }
 
-        loop {
-            let stream = match self.listener.accept().await {
-                Ok((x, _)) => x,
-                Err(e) => {
-                    info!("Error {e:?} while accepting TCP Stream");
-                    continue
+        if headers.get_one("Sec-WebSocket-Version") != Some("13") {
+            request.local_cache(|| "Only WebSocket version 13 is supported".to_string());
+            return Outcome::Failure((Status::BadRequest, ()))
+        }
+
+        let accept_key = match headers.get_one("Sec-WebSocket-Key") {
+            Some(x) => derive_accept_key(x.as_bytes()),
+            None => {
+                request.local_cache(|| "Sec-WebSocket-Key header is empty".to_string());
+                return Outcome::Failure((Status::BadRequest, ()))
+            }
+        };
+
+        // A key that was sent must be valid, so that clients are not silently treated as anonymous
+        let (user, session_protocol) = match find_session_key(request) {
+            Some(key) => {
+                // Browsers fail handshakes that accept none of the offered subprotocols
+                if !offered_protocols(request).any(|protocol| protocol == SESSION_PROTOCOL) {
+                    request.local_cache(|| format!("The {SESSION_PROTOCOL} subprotocol must be offered along with the session key"));
+                    return Outcome::Failure((Status::BadRequest, ()))
                 }
-            };
-
-            let mut handler = None;
-            let stream = match accept_hdr_async(stream, WsCallback{
-                callback_fn: self.verifier,
-                handler: &mut handler
-            }).await {
-                Ok(x) => x,
-                Err(e) => {
-                    info!("Error {e:?} while upgrading TCP Stream to WebSocket");
-                    continue
+
+                let auth: &AuthState = request.rocket().state().unwrap();
+
+                match auth.authenticate(key) {
+                    Some(user) => (Some(user), true),
+                    None => {
+                        request.local_cache(|| "Session key is either invalid or expired".to_string());
+                        return Outcome::Failure((Status::Unauthorized, ()))
+                    }
                 }
-            };
+            }
+            None => (None, false)
+        };
+
+        Outcome::Success(Self {
+            accept_key,
+            session_protocol,
+            user
+        })
+    }
+}
+
+
+/// A WebSocket endpoint, to be added to a `WsRouter`
+#[derive(Clone)]
+pub struct WsRoute {
+    path: String,
+    /// Origins that browsers may connect from. Empty allows any
+    origins: Vec<String>,
+    /// Subprotocols the handler speaks, from the most preferred
+    protocols: Vec<String>,
+    handler: BoxedHandler
+}
+
+
+impl WsRoute {
+    /// Serves sockets connecting to `path` with `handler`
+    ///
+    /// Segments of `path` written as `<name>` match any segment, which reaches the handler in `params`
+    pub fn new<F, Fut>(path: &str, handler: F) -> Self
+    where
+        F: Fn(WsConnection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static
+    {
+        Self {
+            path: path.into(),
+            origins: Vec::new(),
+            protocols: Vec::new(),
+            handler: Arc::new(move |connection| Box::pin(handler(connection)))
+        }
+    }
 
-            if let Some(handler) = handler {
-                (handler)(stream);
+    /// Only lets browsers connect from the given origins, unless there are none
+    pub fn origins(mut self, origins: &[String]) -> Self {
+        self.origins = origins.to_vec();
+        self
+    }
+
+    /// Subprotocols the handler speaks, from the most preferred. Clients that offer none of them are refused
+    pub fn protocols(mut self, protocols: &[&str]) -> Self {
+        self.protocols = protocols.iter().map(|x| x.to_string()).collect();
+        self
+    }
+
+    fn params(&self, request: &Request<'_>) -> HashMap<String, String> {
+        self.path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .enumerate()
+            .filter_map(|(i, segment)| {
+                let name = segment.strip_prefix('<')?.strip_suffix('>')?;
+                Some((name.to_string(), request.routed_segment(i)?.to_string()))
+            })
+            .collect()
+    }
+
+    /// Picks the first subprotocol offered by the client that the handler speaks
+    ///
+    /// Errs if the client only offered subprotocols the handler does not speak
+    fn negotiate(&self, request: &Request<'_>) -> Result<Option<String>, ()> {
+        let offered: Vec<_> = offered_protocols(request)
+            .filter(|protocol| *protocol != SESSION_PROTOCOL && !protocol.starts_with(SESSION_PROTOCOL_PREFIX))
+            .collect();
+
+        if offered.is_empty() {
+            return Ok(None)
+        }
+
+        offered
+            .into_iter()
+            .find(|protocol| self.protocols.iter().any(|x| x == protocol))
+            .map(|protocol| Some(protocol.to_string()))
+            .ok_or(())
+    }
+}
+
+
+/// A route mounted by a `WsRouter`, sharing its limiter
+#[derive(Clone)]
+struct WsEndpoint {
+    route: WsRoute,
+    limiter: Arc<WsLimiter>
+}
+
+
+#[async_trait]
+impl Handler for WsEndpoint {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let upgrade = match request.guard::<WsUpgrade>().await {
+            Outcome::Success(x) => x,
+            Outcome::Failure((status, ())) => return route::Outcome::failure(status),
+            Outcome::Forward(()) => return route::Outcome::forward(data)
+        };
+
+        // Only browsers are made to send an origin, and only they can be tricked into connecting
+        if let Some(origin) = request.headers().get_one("Origin") {
+            if !self.route.origins.is_empty() && !self.route.origins.iter().any(|x| x == origin) {
+                request.local_cache(|| "Origin is not allowed".to_string());
+                return route::Outcome::failure(Status::Forbidden)
+            }
+        }
+
+        let protocol = match self.route.negotiate(request) {
+            Ok(x) => x,
+            Err(()) => {
+                request.local_cache(|| "None of the offered subprotocols are supported".to_string());
+                return route::Outcome::failure(Status::BadRequest)
+            }
+        };
+
+        // Counted last so that connections refused for other reasons do not count as refused here.
+        // The peer address is used rather than client_ip, which trusts a header that clients can set
+        let permit = match self.limiter.admit(request.remote().map(|address| address.ip())) {
+            Ok(x) => x,
+            Err((status, reason)) => {
+                request.local_cache(|| reason.to_string());
+                return route::Outcome::failure(status)
             }
+        };
+
+        route::Outcome::from(request, WsAccept {
+            params: self.route.params(request),
+            protocol,
+            upgrade,
+            permit,
+            handler: self.route.handler.clone()
+        })
+    }
+}
+
+
+/// Collects the WebSocket endpoints of an app, to be mounted like any other routes
+pub struct WsRouter {
+    routes: Vec<WsRoute>,
+    limiter: Arc<WsLimiter>
+}
+
+
+impl WsRouter {
+    pub fn new(limiter: Arc<WsLimiter>) -> Self {
+        Self {
+            routes: Vec::new(),
+            limiter
         }
     }
+
+    pub fn route(mut self, route: WsRoute) -> Self {
+        self.routes.push(route);
+        self
+    }
+}
+
+
+impl From<WsRouter> for Vec<Route> {
+    fn from(router: WsRouter) -> Self {
+        router
+            .routes
+            .into_iter()
+            .map(|route| {
+                let path = route.path.clone();
+                Route::new(Method::Get, &path, WsEndpoint { route, limiter: router.limiter.clone() })
+            })
+            .collect()
+    }
+}
+
+
+struct WsAccept {
+    upgrade: WsUpgrade,
+    params: HashMap<String, String>,
+    protocol: Option<String>,
+    permit: WsPermit,
+    handler: BoxedHandler
+}
+
+
+impl<'r> Responder<'r, 'static> for WsAccept {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut builder = Response::build();
+
+        builder
+            .status(Status::SwitchingProtocols)
+            .raw_header("Sec-WebSocket-Accept", self.upgrade.accept_key);
+
+        // Either is one that the client offered
+        if let Some(protocol) = &self.protocol {
+            builder.raw_header("Sec-WebSocket-Protocol", protocol.clone());
+        } else if self.upgrade.session_protocol {
+            builder.raw_header("Sec-WebSocket-Protocol", SESSION_PROTOCOL);
+        }
+
+        builder
+            .upgrade("websocket", WsIoHandler {
+                user: self.upgrade.user,
+                params: self.params,
+                protocol: self.protocol,
+                permit: self.permit,
+                handler: self.handler
+            })
+            .ok()
+    }
+}
+
+
+/// Wraps the upgraded connection once Rocket has sent the handshake response
+///
+/// Rocket drops this if the upgrade fails, which frees the permit
+struct WsIoHandler {
+    user: Option<AuthenticatedUser>,
+    params: HashMap<String, String>,
+    protocol: Option<String>,
+    permit: WsPermit,
+    handler: BoxedHandler
+}
+
+
+#[async_trait]
+impl IoHandler for WsIoHandler {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let this = *Pin::into_inner(self);
+
+        let stream = WebSocketStream::from_raw_socket(TrackedStream { io, _permit: this.permit }, Role::Server, None).await;
+
+        (this.handler)(WsConnection {
+            stream,
+            user: this.user,
+            params: this.params,
+            protocol: this.protocol
+        }).await;
+
+        Ok(())
+    }
 }
 
 
 pub static PING_INTERVAL: OnceCell<Duration> = OnceCell::new();
+/// How long after `PING_INTERVAL` a socket has to answer a ping before it is dropped
+pub static PONG_TIMEOUT: OnceCell<Duration> = OnceCell::new();
+/// How many messages can wait to be sent to one socket of a `WsHub`
+pub static QUEUE_CAPACITY: OnceCell<usize> = OnceCell::new();
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+pub static SLOW_CONSUMER_POLICY: OnceCell<SlowConsumerPolicy> = OnceCell::new();
+/// How long a socket being dropped has to acknowledge the close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many text messages from one socket can wait for its handler before the socket stops being read
+const INCOMING_CAPACITY: usize = 16;
+
+
+/// What happens when a message is sent to a socket whose queue is full
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Forgets the oldest queued message to make room
+    #[default]
+    DropOldest,
+    /// Drops the socket
+    Disconnect
+}
+
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Arc<Message>>,
+    /// Set while the first message is one given to `Outbox::release`, which is never dropped to make room
+    pinned: bool
+}
+
+
+/// Messages waiting to be sent to one socket
+///
+/// Messages are shared between every queue they were broadcast to, and only copied when written
+#[derive(Default)]
+struct Outbox {
+    queue: std::sync::Mutex<Queue>,
+    ready: Notify,
+    closed: AtomicBool,
+    /// Messages are queued but not sent while set
+    held: AtomicBool,
+    policy: SlowConsumerPolicy
+}
+
+
+impl Outbox {
+    /// Returns false if the socket should be dropped
+    fn push(&self, message: Arc<Message>) -> bool {
+        if self.closed.load(Ordering::Acquire) {
+            return false
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+
+        if queue.messages.len() >= *QUEUE_CAPACITY.get().unwrap() {
+            match self.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    let oldest = if queue.pinned { 1 } else { 0 };
+                    queue.messages.remove(oldest);
+                }
+                SlowConsumerPolicy::Disconnect => return false
+            }
+        }
+
+        queue.messages.push_back(message);
+        drop(queue);
+        self.ready.notify_one();
+        true
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.ready.notify_one();
+    }
+
+    fn hold(&self) {
+        self.held.store(true, Ordering::Release);
+    }
+
+    /// Queues the message ahead of every other and resumes sending. Returns false if the socket should be dropped
+    ///
+    /// The message is kept until sent, even past the capacity of the queue
+    fn release(&self, first: Arc<Message>) -> bool {
+        if self.closed.load(Ordering::Acquire) {
+            return false
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.messages.push_front(first);
+        queue.pinned = true;
+        drop(queue);
+        self.held.store(false, Ordering::Release);
+        self.ready.notify_one();
+        true
+    }
+
+    /// Waits for the next message, or returns None once closed. Queued messages are not sent after closing
+    async fn next(&self) -> Option<Arc<Message>> {
+        loop {
+            if self.closed.load(Ordering::Acquire) {
+                return None
+            }
+
+            if !self.held.load(Ordering::Acquire) {
+                let mut queue = self.queue.lock().unwrap();
+                let message = queue.messages.pop_front();
+
+                if message.is_some() {
+                    queue.pinned = false;
+                    return message
+                }
+            }
+
+            self.ready.notified().await;
+        }
+    }
+}
+
+
+/// A socket in a `WsHub`, written to by its own task
+struct Member {
+    outbox: Arc<Outbox>,
+    /// When the socket last answered a ping, or joined
+    last_pong: Arc<std::sync::Mutex<Instant>>,
+    reader: JoinHandle<()>
+}
 
 
-pub struct WsList {
-    sockets: Arc<Mutex<Vec<WebSocket>>>,
+impl Drop for Member {
+    /// The writer closes the socket once its outbox is closed
+    fn drop(&mut self) {
+        self.outbox.close();
+        self.reader.abort();
+    }
+}
+
+
+type Members = DashMap<u64, Member>;
+
+
+/// A socket added to a `WsHub`
+pub struct WsMember {
+    pub id: u64,
+    /// Text messages from the client, until the socket leaves the hub
+    pub incoming: mpsc::Receiver<String>
+}
+
+/// Ids of the sockets subscribed to each topic
+type Topics = DashMap<String, HashSet<u64>>;
+
+
+/// Sockets subscribed to named topics, which any handler can publish to
+///
+/// Managed by Rocket, and given to WebSocket handlers through `WsContext`.
+/// The topics map is never locked while a member is, only the other way around
+pub struct WsHub {
+    members: Arc<Members>,
+    topics: Arc<Topics>,
+    next_id: AtomicU64,
     _ping_handle: JoinHandle<()>
 }
 
 
-impl Drop for WsList {
+impl Drop for WsHub {
     fn drop(&mut self) {
         self._ping_handle.abort();
     }
 }
 
 
-impl WsList {
+impl WsHub {
     pub fn new() -> Self {
-        let sockets: Arc<Mutex<Vec<WebSocket>>> = Default::default();
-        let sockets_clone = sockets.clone();
+        let members: Arc<Members> = Default::default();
+        let topics: Arc<Topics> = Default::default();
+        let members_clone = members.clone();
+        let topics_clone = topics.clone();
 
-        WsList {
-            sockets,
+        WsHub {
+            members,
+            topics,
+            next_id: AtomicU64::new(0),
             _ping_handle: spawn(async move {
                 let duration = *PING_INTERVAL.get().unwrap();
+                let deadline = duration + *PONG_TIMEOUT.get().unwrap();
+                let ping = Arc::new(Message::Ping("Ping!".as_bytes().into()));
+
                 loop {
                     sleep(duration).await;
-                    
-                    Self::send_all_internal(sockets_clone.deref(), Message::Ping("Ping!".as_bytes().into())).await;
+
+                    members_clone.retain(|_, member| {
+                        member.last_pong.lock().unwrap().elapsed() < deadline && member.outbox.push(ping.clone())
+                    });
+                    // Forgets sockets that left since
+                    topics_clone.retain(|_, ids| {
+                        ids.retain(|id| members_clone.contains_key(id));
+                        !ids.is_empty()
+                    });
                 }
             })
         }
     }
 
-    pub async fn add_ws(&self, socket: WebSocket) {
-        self.sockets.lock().await.push(socket);
+    /// Subscribes the socket to every topic, until it closes or is dropped
+    ///
+    /// Handlers that do not read what the client sends can ignore the returned member
+    pub fn add_ws<S>(&self, socket: WebSocketStream<S>, topics: &[&str]) -> WsMember
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+        self.add_ws_with_policy(socket, topics, *SLOW_CONSUMER_POLICY.get().unwrap())
     }
 
-    pub async fn send_all(&self, message: Message) {
-        Self::send_all_internal(self.sockets.deref(), message).await;
+    /// Like `add_ws`, but handles the socket falling behind with `policy` instead of the configured one
+    ///
+    /// For sockets that cannot miss a message, such as ones sent deltas, which need `SlowConsumerPolicy::Disconnect`
+    pub fn add_ws_with_policy<S>(&self, socket: WebSocketStream<S>, topics: &[&str], policy: SlowConsumerPolicy) -> WsMember
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (mut sink, mut stream) = socket.split();
+        let outbox = Arc::new(Outbox {
+            policy,
+            ..Default::default()
+        });
+        let last_pong = Arc::new(std::sync::Mutex::new(Instant::now()));
+        let outbox_clone = outbox.clone();
+
+        spawn(async move {
+            while let Some(message) = outbox_clone.next().await {
+                if sink.send(message.as_ref().clone()).await.is_err() {
+                    break
+                }
+            }
+
+            // Later publishes see the closed outbox and drop the socket
+            outbox_clone.close();
+            // Starts the close handshake unless the client already did
+            let _ = timeout(CLOSE_TIMEOUT, sink.close()).await;
+        });
+
+        let members = self.members.clone();
+        let last_pong_clone = last_pong.clone();
+        let (incoming_sender, incoming) = mpsc::channel(INCOMING_CAPACITY);
+
+        // Reading is what answers pings and close frames from the client
+        let reader = spawn(async move {
+            while let Some(Ok(message)) = stream.next().await {
+                match message {
+                    Message::Pong(_) => *last_pong_clone.lock().unwrap() = Instant::now(),
+                    Message::Close(_) => break,
+                    // Fails straight away if the handler dropped its receiver
+                    Message::Text(text) => {
+                        let _ = incoming_sender.send(text).await;
+                    }
+                    _ => {}
+                }
+            }
+
+            members.remove(&id);
+        });
+
+        self.members.insert(id, Member {
+            outbox,
+            last_pong,
+            reader
+        });
+
+        for topic in topics {
+            self.topics.entry(topic.to_string()).or_default().insert(id);
+        }
+
+        WsMember { id, incoming }
     }
 
-    async fn send_all_internal(lock: &Mutex<Vec<WebSocket>>, message: Message) {
-        let mut lock = lock.lock().await;
-        let new_vec = Vec::with_capacity(lock.len());
-        let sockets = replace(lock.deref_mut(), new_vec);
+    /// Subscribes a socket to the topic, sending it what `first` resolves to before anything published there from now on
+    ///
+    /// Lets a handler send a snapshot without missing what changed while it was read. The first message is never
+    /// dropped, but what is published meanwhile is subject to the socket's policy when its queue fills.
+    /// Returns false if the socket is gone
+    pub async fn subscribe<F>(&self, id: u64, topic: &str, first: F) -> bool
+    where
+        F: Future<Output = Message>
+    {
+        let outbox = match self.members.get(&id) {
+            Some(member) => member.outbox.clone(),
+            None => return false
+        };
 
-        let handles: Vec<_> = sockets
-            .into_iter()
-            .map(|mut ws| {
-                let message = message.clone();
-
-                spawn(async move {
-                    if ws.send(message).await.is_err() {
-                        None
-                    } else {
-                        Some(ws)
+        outbox.hold();
+        self.topics.entry(topic.to_string()).or_default().insert(id);
+
+        if outbox.release(Arc::new(first.await)) {
+            true
+        } else {
+            self.members.remove(&id);
+            false
+        }
+    }
+
+    pub fn unsubscribe(&self, id: u64, topic: &str) {
+        self.topics.remove_if_mut(topic, |_, ids| {
+            ids.remove(&id);
+            ids.is_empty()
+        });
+    }
+
+    /// Names of the topics that have subscribers
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.iter().map(|entry| entry.key().clone()).collect()
+    }
+
+    /// Queues a message for one socket. Returns false if the socket is gone
+    pub fn send(&self, id: u64, message: Message) -> bool {
+        let queued = self.members
+            .get(&id)
+            .map_or(false, |member| member.outbox.push(Arc::new(message)));
+
+        if !queued {
+            self.members.remove(&id);
+        }
+        queued
+    }
+
+    /// Queues the message for every socket subscribed to the topic without waiting for any of them
+    ///
+    /// Drops sockets that are gone, or too far behind under `SlowConsumerPolicy::Disconnect`.
+    /// A message published to several topics can be shared between them instead of copied
+    pub fn publish(&self, topic: &str, message: impl Into<Arc<Message>>) {
+        let message = message.into();
+        let mut dropped = Vec::new();
+
+        if let Some(ids) = self.topics.get(topic) {
+            for id in ids.iter() {
+                let queued = self.members
+                    .get(id)
+                    .map_or(false, |member| member.outbox.push(message.clone()));
+
+                if !queued {
+                    dropped.push(*id);
+                }
+            }
+        }
+
+        if dropped.is_empty() {
+            return
+        }
+
+        for id in dropped.iter() {
+            self.members.remove(id);
+        }
+
+        self.topics.remove_if_mut(topic, |_, ids| {
+            ids.retain(|id| !dropped.contains(id));
+            ids.is_empty()
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{Build, Rocket, State};
+    use rocket::config::{Config, LogLevel};
+    use rocket::fairing::AdHoc;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client as LocalClient;
+    use rocket::tokio::{join, net::{TcpListener, TcpStream}, sync::oneshot};
+    use tokio_tungstenite::{accept_async, client_async, connect_async};
+    use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest, handshake::client::Request as ClientRequest, http::HeaderValue};
+
+    const ALLOWED_ORIGIN: &str = "https://allowed.example";
+
+    fn configure() {
+        let _ = PING_INTERVAL.set(Duration::from_secs(60));
+        let _ = PONG_TIMEOUT.set(Duration::from_secs(60));
+        let _ = QUEUE_CAPACITY.set(DEFAULT_QUEUE_CAPACITY);
+        let _ = SLOW_CONSUMER_POLICY.set(SlowConsumerPolicy::DropOldest);
+    }
+
+    /// Connects a client to a socket added to the hub
+    async fn connect(hub: &WsHub, topics: &[&str]) -> (WebSocketStream<TcpStream>, WsMember) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (client, server) = join!(
+            async {
+                let stream = TcpStream::connect(address).await.unwrap();
+                client_async(format!("ws://{address}/"), stream).await.unwrap().0
+            },
+            async {
+                accept_async(listener.accept().await.unwrap().0).await.unwrap()
+            }
+        );
+
+        (client, hub.add_ws(server, topics))
+    }
+
+    async fn receive<S: AsyncRead + AsyncWrite + Unpin>(client: &mut WebSocketStream<S>) -> String {
+        match timeout(Duration::from_secs(5), client.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => text,
+            other => panic!("Expected a text message, got {other:?}")
+        }
+    }
+
+    async fn assert_nothing_received<S: AsyncRead + AsyncWrite + Unpin>(client: &mut WebSocketStream<S>) {
+        assert!(timeout(Duration::from_millis(200), client.next()).await.is_err());
+    }
+
+    #[rocket::post("/publish/<topic>", data = "<message>")]
+    fn publish_route(topic: &str, message: String, hub: &State<Arc<WsHub>>) {
+        hub.publish(topic, Message::Text(message));
+    }
+
+    /// Serves `/ws/echo/<name>`, which greets with its params and stays open, and `/ws/topics/<topic>`, which joins the hub
+    fn test_rocket(hub: Arc<WsHub>, limiter: Arc<WsLimiter>) -> Rocket<Build> {
+        let config = Config {
+            port: 0,
+            log_level: LogLevel::Off,
+            ..Config::debug_default()
+        };
+        let topic_hub = hub.clone();
+
+        let router = WsRouter::new(limiter)
+            .route(
+                WsRoute::new("/echo/<name>", |mut connection: WsConnection| async move {
+                    let greeting = format!("{}/{}", connection.params["name"], connection.protocol.unwrap_or_default());
+
+                    if connection.stream.send(Message::Text(greeting)).await.is_ok() {
+                        while let Some(Ok(_)) = connection.stream.next().await {}
                     }
                 })
-            })
-            .collect();
-        
-        for handle in handles {
-            match handle.await.unwrap() {
-                Some(x) => lock.push(x),
-                None => continue
-            }
+                    .protocols(&["echo.v2", "echo.v1"])
+                    .origins(&[ALLOWED_ORIGIN.to_string()])
+            )
+            .route(WsRoute::new("/topics/<topic>", move |connection: WsConnection| {
+                topic_hub.add_ws(connection.stream, &[&connection.params["topic"]]);
+                async {}
+            }));
+
+        rocket::custom(config)
+            .manage(hub)
+            .mount("/ws", router)
+            .mount("/", rocket::routes![publish_route])
+    }
+
+    /// Serves the Rocket on a free port, which is returned once it listens
+    async fn launch(rocket: Rocket<Build>) -> u16 {
+        let (sender, port) = oneshot::channel();
+
+        spawn(rocket
+            .attach(AdHoc::on_liftoff("Report Port", |rocket| Box::pin(async move {
+                let _ = sender.send(rocket.config().port);
+            })))
+            .launch());
+
+        port.await.unwrap()
+    }
+
+    fn request(port: u16, path: &str, headers: &[(&'static str, &'static str)]) -> ClientRequest {
+        let mut request = format!("ws://127.0.0.1:{port}{path}").into_client_request().unwrap();
+
+        for (name, value) in headers {
+            request.headers_mut().insert(*name, HeaderValue::from_static(value));
         }
+        request
+    }
+
+    /// The status a handshake was refused with
+    async fn refusal(request: ClientRequest) -> u16 {
+        match connect_async(request).await {
+            Err(tungstenite::Error::Http(response)) => response.status().as_u16(),
+            Err(e) => panic!("Expected an HTTP error, got {e}"),
+            Ok(_) => panic!("Handshake was accepted")
+        }
+    }
+
+    /// Dispatches a handshake without upgrading
+    async fn handshake_status(client: &LocalClient, uri: &'static str, headers: &[(&'static str, &'static str)]) -> Status {
+        let mut request = client
+            .get(uri)
+            .header(Header::new("Upgrade", "websocket"))
+            .header(Header::new("Connection", "Upgrade"))
+            .header(Header::new("Sec-WebSocket-Version", "13"))
+            .header(Header::new("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="));
+
+        for (name, value) in headers {
+            request = request.header(Header::new(*name, *value));
+        }
+        request.dispatch().await.status()
+    }
+
+    #[rocket::async_test]
+    async fn publish_reaches_only_subscribers() {
+        configure();
+        let hub = WsHub::new();
+        let (mut subscribed, _) = connect(&hub, &["scores"]).await;
+        let (mut other, _) = connect(&hub, &["friends/alice"]).await;
+
+        hub.publish("scores", Message::Text("new score".into()));
+
+        assert_eq!(receive(&mut subscribed).await, "new score");
+        assert_nothing_received(&mut other).await;
+    }
+
+    #[rocket::async_test]
+    async fn socket_receives_every_topic_it_subscribed_to() {
+        configure();
+        let hub = WsHub::new();
+        let (mut client, _) = connect(&hub, &["scores", "friends/alice"]).await;
+
+        hub.publish("scores", Message::Text("first".into()));
+        hub.publish("friends/alice", Message::Text("second".into()));
+        hub.publish("friends/bob", Message::Text("third".into()));
+
+        assert_eq!(receive(&mut client).await, "first");
+        assert_eq!(receive(&mut client).await, "second");
+        assert_nothing_received(&mut client).await;
+    }
+
+    #[rocket::async_test]
+    async fn unsubscribed_sockets_stop_receiving() {
+        configure();
+        let hub = WsHub::new();
+        let (mut client, member) = connect(&hub, &["scores"]).await;
+
+        hub.unsubscribe(member.id, "scores");
+        hub.publish("scores", Message::Text("missed".into()));
+
+        assert!(hub.topics().is_empty());
+        assert_nothing_received(&mut client).await;
+        assert!(hub.send(member.id, Message::Text("still here".into())));
+        assert_eq!(receive(&mut client).await, "still here");
+    }
+
+    #[rocket::async_test]
+    async fn subscribe_sends_first_message_before_updates() {
+        configure();
+        let hub = WsHub::new();
+        let (mut client, member) = connect(&hub, &[]).await;
+
+        // Published while the snapshot is being read
+        let subscribed = hub.subscribe(member.id, "scores", async {
+            hub.publish("scores", Message::Text("update".into()));
+            Message::Text("snapshot".into())
+        }).await;
+
+        assert!(subscribed);
+        assert_eq!(receive(&mut client).await, "snapshot");
+        assert_eq!(receive(&mut client).await, "update");
+    }
+
+    #[rocket::async_test]
+    async fn first_message_survives_a_full_queue() {
+        configure();
+        let hub = WsHub::new();
+        let (mut client, member) = connect(&hub, &[]).await;
+
+        // Fills the queue while it is held, so that the oldest messages are dropped
+        hub.subscribe(member.id, "scores", async {
+            for i in 0..DEFAULT_QUEUE_CAPACITY * 2 {
+                hub.publish("scores", Message::Text(i.to_string()));
+            }
+            Message::Text("snapshot".into())
+        }).await;
+
+        assert_eq!(receive(&mut client).await, "snapshot");
+        assert_eq!(receive(&mut client).await, DEFAULT_QUEUE_CAPACITY.to_string());
+    }
+
+    #[rocket::async_test]
+    async fn text_from_clients_reaches_the_handler() {
+        configure();
+        let hub = WsHub::new();
+        let (mut client, mut member) = connect(&hub, &[]).await;
+
+        client.send(Message::Text("subscribe".into())).await.unwrap();
+
+        let text = timeout(Duration::from_secs(5), member.incoming.recv()).await.unwrap();
+        assert_eq!(text.as_deref(), Some("subscribe"));
+    }
+
+    #[rocket::async_test]
+    async fn closed_sockets_leave_the_hub() {
+        configure();
+        let hub = WsHub::new();
+        let (mut client, mut member) = connect(&hub, &["scores"]).await;
+
+        client.close(None).await.unwrap();
+
+        timeout(Duration::from_secs(5), async {
+            while !hub.members.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Socket was not removed");
+
+        hub.publish("scores", Message::Text("gone".into()));
+
+        assert!(hub.topics.is_empty());
+        // The handler sees the socket leave
+        assert_eq!(member.incoming.recv().await, None);
+    }
+
+    #[rocket::async_test]
+    async fn router_passes_params_and_negotiated_protocol() {
+        configure();
+        let port = launch(test_rocket(Arc::new(WsHub::new()), Default::default())).await;
+
+        let (mut client, response) = connect_async(request(port, "/ws/echo/alice", &[
+            ("Origin", ALLOWED_ORIGIN),
+            ("Sec-WebSocket-Protocol", "echo.v3, echo.v1")
+        ])).await.unwrap();
+
+        assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "echo.v1");
+        assert_eq!(receive(&mut client).await, "alice/echo.v1");
+    }
+
+    #[rocket::async_test]
+    async fn handler_publishes_to_sockets_of_the_router() {
+        configure();
+        let hub = Arc::new(WsHub::new());
+        let port = launch(test_rocket(hub.clone(), Default::default())).await;
+        let local = LocalClient::untracked(test_rocket(hub.clone(), Default::default())).await.unwrap();
+
+        let (mut client, _) = connect_async(request(port, "/ws/topics/scores", &[])).await.unwrap();
+
+        // The socket joins the hub after the handshake completes
+        timeout(Duration::from_secs(5), async {
+            while hub.topics().is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Socket did not join the hub");
+
+        let response = local.post("/publish/scores").body("new score").dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(receive(&mut client).await, "new score");
+    }
+
+    #[rocket::async_test]
+    async fn handshakes_are_checked_before_upgrading() {
+        configure();
+        let client = LocalClient::untracked(test_rocket(Arc::new(WsHub::new()), Default::default())).await.unwrap();
+
+        assert_eq!(client.get("/ws/echo/alice").dispatch().await.status(), Status::UpgradeRequired);
+        assert_eq!(handshake_status(&client, "/ws/echo/alice", &[("Origin", "https://other.example")]).await, Status::Forbidden);
+        assert_eq!(handshake_status(&client, "/ws/echo/alice", &[("Sec-WebSocket-Protocol", "echo.v3")]).await, Status::BadRequest);
+        // The key would be echoed back if the marker was not offered
+        assert_eq!(handshake_status(&client, "/ws/echo/alice", &[("Sec-WebSocket-Protocol", "session.key")]).await, Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn limiter_refuses_connections_until_one_closes() {
+        configure();
+        let port = launch(test_rocket(Arc::new(WsHub::new()), Arc::new(WsLimiter::new(1, 0)))).await;
+
+        let (mut first, _) = connect_async(request(port, "/ws/echo/first", &[])).await.unwrap();
+        assert_eq!(receive(&mut first).await, "first/");
+        assert_eq!(refusal(request(port, "/ws/echo/second", &[])).await, 503);
+
+        first.close(None).await.unwrap();
+
+        // The place is freed once the handler returns
+        timeout(Duration::from_secs(5), async {
+            while connect_async(request(port, "/ws/echo/third", &[])).await.is_err() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Connection was not let in after the first closed");
+    }
+
+    #[rocket::async_test]
+    async fn limiter_refuses_too_many_connections_from_one_address() {
+        configure();
+        let port = launch(test_rocket(Arc::new(WsHub::new()), Arc::new(WsLimiter::new(0, 1)))).await;
+
+        let (mut first, _) = connect_async(request(port, "/ws/echo/first", &[])).await.unwrap();
+        assert_eq!(receive(&mut first).await, "first/");
+        assert_eq!(refusal(request(port, "/ws/echo/second", &[])).await, 429);
     }
 }